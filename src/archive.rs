@@ -0,0 +1,103 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::pak;
+
+/// A directory that is deleted when dropped, i.e. the one archives are extracted into for the duration of a build
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` tells apart the temporary directories of the same process
+    pub fn new(name: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("upmm-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create directory '{}'", path.display()))?;
+
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A .zip mod extracted into a directory. The .pak files in it are extracted the same way as standalone .pak mods and
+/// the loose files are copied the same way as directory mods
+pub(crate) struct ExtractedArchive {
+    pub dir: PathBuf,
+    /// The .pak files in the archive, sorted by path. When more than one contains the same file, the last one wins
+    pub paks: Vec<PathBuf>,
+    /// The .utoc files of the IoStore containers in the archive
+    pub utocs: Vec<PathBuf>,
+    /// The files that aren't part of a .pak or IoStore container, relative to `dir`
+    pub loose_files: Vec<PathBuf>,
+}
+
+/// Extracts a .zip file into `output_dir`, which is replaced if it already exists
+pub(crate) fn extract_zip(zip_path: &Path, output_dir: &Path) -> Result<ExtractedArchive> {
+    let name = zip_path
+        .file_name()
+        .context("Failed to get the archive's file name")?
+        .to_string_lossy();
+
+    let file = File::open(zip_path)
+        .with_context(|| format!("Failed to open zip file '{}'", zip_path.display()))?;
+    let mut archive = zip::ZipArchive::new(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to read zip file '{}'", zip_path.display()))?;
+
+    if output_dir.exists() {
+        fs::remove_dir_all(output_dir)
+            .with_context(|| format!("Failed to delete directory '{}'", output_dir.display()))?;
+    }
+
+    log::info!("{}: Extracting {} archive entries", name, archive.len());
+
+    // Entries with paths that would end up outside of the output directory are rejected by the zip crate
+    archive
+        .extract(output_dir)
+        .with_context(|| format!("Failed to extract zip file '{}'", zip_path.display()))?;
+
+    let mut files = Vec::new();
+    pak::collect_files(output_dir, &mut files)?;
+    files.sort();
+
+    let mut extracted = ExtractedArchive {
+        dir: output_dir.to_path_buf(),
+        paks: Vec::new(),
+        utocs: Vec::new(),
+        loose_files: Vec::new(),
+    };
+
+    for file in files {
+        let relative_path = file.strip_prefix(output_dir)?.to_path_buf();
+
+        if pak::has_extension(&file, "pak") {
+            extracted.paks.push(file);
+        } else if pak::has_extension(&file, "utoc") {
+            extracted.utocs.push(file);
+        } else if pak::has_extension(&file, "ucas") {
+            // The .ucas files are read through their .utoc
+        } else if relative_path.components().count() == 1 {
+            // Archives usually come with a readme next to the mod, which shouldn't end up in the modpack
+            log::warn!(
+                "{}: Skipping {}, only files in folders are added to the modpack",
+                name,
+                relative_path.display()
+            );
+        } else {
+            extracted.loose_files.push(relative_path);
+        }
+    }
+
+    Ok(extracted)
+}
@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use git2::{ObjectType, Oid};
+use serde::{Deserialize, Serialize};
+
+use crate::{git, iostore, pak};
+
+/// The name of the file in the staging repository's .git directory that the state of the last build is kept in
+const STATE_FILE_NAME: &str = "upmm_state.json";
+
+/// The commits of master and the originals branch at a point in the build
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub master: String,
+    pub originals: String,
+}
+
+impl Checkpoint {
+    pub fn of(repo: &git2::Repository) -> Result<Self> {
+        let commit = |branch: &str| -> Result<String> {
+            Ok(repo
+                .find_branch(branch, git2::BranchType::Local)?
+                .get()
+                .peel_to_commit()?
+                .id()
+                .to_string())
+        };
+
+        Ok(Self {
+            master: commit("master")?,
+            originals: commit(git::ORIGINALS_BRANCH)?,
+        })
+    }
+}
+
+/// How a single mod was merged in the last build
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModState {
+    pub branch: String,
+    /// The hash of the mod's source files, see [`source_hash`]
+    pub source_hash: String,
+    /// The files the mod added to the staging directory
+    pub files: Vec<String>,
+    /// The commit on the mod's branch that contains its files
+    pub branch_commit: String,
+    /// The files the mod changed
+    pub changed_files: Vec<String>,
+    pub conflicts: Vec<git::FileConflicts>,
+    pub containers: Vec<iostore::Container>,
+    /// The mount points of the mod's .pak files
+    #[serde(default)]
+    pub mount_points: Vec<String>,
+    /// The state of the repository once the mod was merged
+    pub checkpoint: Checkpoint,
+}
+
+/// What the last build did, so the next build can reuse the merges of the mods that didn't change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildState {
+    /// The hash of everything other than the mods that affects the build, see [`settings_hash`]
+    pub settings_hash: String,
+    /// The commit the repository was created with
+    pub initial: String,
+    /// The state of the repository once the vanilla files were added, before any mod was merged
+    pub start: Checkpoint,
+    /// The mods in the order they were merged
+    pub mods: Vec<ModState>,
+}
+
+impl BuildState {
+    pub fn path(git_dir: &Path) -> std::path::PathBuf {
+        git_dir.join(STATE_FILE_NAME)
+    }
+
+    /// Returns None if there is no state or it can't be read, in which case everything is rebuilt
+    pub fn read(git_dir: &Path) -> Option<Self> {
+        let contents = std::fs::read_to_string(Self::path(git_dir)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn write(&self, git_dir: &Path) -> Result<()> {
+        let path = Self::path(git_dir);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write build state '{}'", path.display()))
+    }
+
+    /// Removes the state, so a build that fails half way through isn't reused
+    pub fn remove(git_dir: &Path) -> Result<()> {
+        let path = Self::path(git_dir);
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove build state '{}'", path.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn hash_str(s: &str) -> Result<String> {
+    Ok(Oid::hash_object(ObjectType::Blob, s.as_bytes())?.to_string())
+}
+
+pub(crate) fn hash_file(path: &Path) -> Result<String> {
+    Ok(Oid::hash_file(ObjectType::Blob, path)
+        .with_context(|| format!("Failed to hash '{}'", path.display()))?
+        .to_string())
+}
+
+/// Hashes the contents of a mod and its options. For directories every file is hashed, for .pak files the .pak and its
+/// .utoc are hashed (the .ucas isn't, since it can't change without the .utoc changing)
+pub fn source_hash(path: &Path, aes_key: Option<&str>, root: Option<&str>) -> Result<String> {
+    let mut manifest = format!(
+        "aes_key {}\nroot {}\n",
+        aes_key.unwrap_or_default(),
+        root.unwrap_or_default()
+    );
+
+    if path.is_dir() {
+        let mut files = Vec::new();
+        pak::collect_files(path, &mut files)?;
+        files.sort();
+
+        for file in files {
+            let relative_path = file.strip_prefix(path)?;
+            manifest += &format!("{} {}\n", relative_path.display(), hash_file(&file)?);
+        }
+    } else {
+        manifest += &format!("{} {}\n", path.display(), hash_file(path)?);
+
+        let utoc_path = path.with_extension("utoc");
+        if utoc_path != path && utoc_path.exists() {
+            manifest += &format!("{} {}\n", utoc_path.display(), hash_file(&utoc_path)?);
+        }
+    }
+
+    hash_str(&manifest)
+}
+
+/// Hashes the settings that affect every mod. When they change, everything is rebuilt
+pub fn settings_hash(
+    aes_key: Option<&str>,
+    formats: &str,
+    game_root: &str,
+    base_paks: &[std::path::PathBuf],
+    vanilla_files: &[String],
+) -> Result<String> {
+    let mut manifest = format!(
+        "version {}\naes_key {}\nformats {}\ngame_root {}\n",
+        env!("CARGO_PKG_VERSION"),
+        aes_key.unwrap_or_default(),
+        formats,
+        game_root
+    );
+
+    // The game's paks are too big to hash on every build, so their size and modification time are used instead
+    for pak_path in base_paks {
+        let metadata = std::fs::metadata(pak_path)
+            .with_context(|| format!("Failed to read base pak '{}'", pak_path.display()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+
+        manifest += &format!(
+            "base_pak {} {} {}\n",
+            pak_path.display(),
+            metadata.len(),
+            modified
+        );
+    }
+
+    for file in vanilla_files {
+        manifest += &format!("vanilla {}\n", file);
+    }
+
+    hash_str(&manifest)
+}
@@ -0,0 +1,558 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+
+use crate::error::ErrorKind;
+
+/// Mod-specific options, set with mods.<mod_name> in the config file
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct UpmmModConfig {
+    /// The priority of the mod
+    /// Lower numbers are merged first, meaning changes in mod priority=2 will take priority over changes in mod priority=1
+    /// Without an explicit priority set, the mods priority is set via alphabetical order
+    /// For example, "a.pak", "b.pak", and "c.pak" will have priorities 0, 1, and 2 respectively
+    /// As such, it's recommended to set priorities above 1000 and below -1000 to ensure adding new mods won't affect existing priorities
+    pub priority: Option<i64>,
+
+    /// The AES key used to decrypt the mod's .pak, as hex (optionally prefixed with 0x) or base64
+    /// Overrides the top-level aes_key
+    pub aes_key: Option<String>,
+
+    /// Set to false to skip the mod without removing it from mods_dir
+    pub enabled: Option<bool>,
+
+    /// The folder within a directory or .zip mod that corresponds to the game root (the folder containing Stalker2)
+    /// Without it, the first folder containing Stalker2, Engine, Content or GameLite is used
+    pub root: Option<String>,
+}
+
+/// A named set of overrides, set with profiles.<profile_name> in the config file and selected with --profile
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct UpmmProfile {
+    /// The name of the modpack. Defaults to <name>_<profile_name>
+    pub name: Option<String>,
+
+    /// The staging directory of the profile. Defaults to <staging_dir>_<profile_name>, so each profile keeps its own
+    /// history and can be rebuilt incrementally
+    pub staging_dir: Option<String>,
+
+    /// Overrides copy_to_dir
+    pub copy_to_dir: Option<String>,
+
+    /// profiles.<profile_name>.mods.<mod_name> overrides the options in mods.<mod_name>, only the options that are set
+    /// are changed
+    pub mods: Option<HashMap<String, UpmmModConfig>>,
+
+    /// Replaces the top-level disabled list
+    pub disabled: Option<Vec<String>>,
+}
+
+/// The contents of the config file. Relative directories are resolved against the directory of the config file
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct UpmmConfig {
+    /// The name of the modpack
+    pub name: String,
+
+    /// Prepended to the name of the .pak file, i.e. "zzz_" so the modpack sorts after the mods it's installed with
+    pub pak_prefix: Option<String>,
+
+    /// Whether the name of the .pak file ends with _P, which Unreal requires for a pak to override the game's files
+    /// Defaults to true
+    pub patch_suffix: Option<bool>,
+
+    /// The directory where all of the files are staged before being added to the .pak file
+    /// This directory will be a git repository so you can use git to look at the history of the files
+    /// Each input mod will contain it's own branch and merge commit
+    /// The directory is kept between builds so only the mods that changed, and the mods merged after them, are merged again
+    pub staging_dir: String,
+
+    /// The directory where the mods are located
+    /// The directory can contain either:
+    /// - Directories that are essentially unpacked .pak files (assumes default mount point of "../../../")
+    /// - .pak files
+    /// - .pak/.utoc/.ucas triplets (the IoStore assets can't be merged, but overlaps between mods are reported)
+    /// - .zip archives containing any of the above. The archive's name is used as the mod's name
+    pub mods_dir: String,
+
+    /// mods.<mod_name> allows you to set mod-specific options
+    pub mods: Option<HashMap<String, UpmmModConfig>>,
+
+    /// The names of mods in mods_dir to skip, the same as setting mods.<mod_name>.enabled = false
+    pub disabled: Option<Vec<String>>,
+
+    /// The path to copy the .pok to
+    /// TODO: Find the installation path. For Steam it's in
+    /// HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows\CurrentVersion\Uninstall\Steam App 1643320 | REG_SZ InstallLocation
+    pub copy_to_dir: Option<String>,
+
+    /// A directory containing the game's own .pak files (i.e. Stalker2/Content/Paks)
+    /// The vanilla version of every file touched by a mod is extracted from these paks and used as the base of the merge
+    /// When there are multiple paks containing the same file, the one that sorts last wins
+    pub base_paks_dir: Option<String>,
+
+    /// A single game .pak file to use as the merge base. Can be used together with base_paks_dir, in which case it takes priority
+    pub base_pak: Option<String>,
+
+    /// The AES key used to decrypt encrypted .pak files, as hex (optionally prefixed with 0x) or base64
+    /// This is used for the base paks and for any mod that doesn't set mods.<mod_name>.aes_key
+    pub aes_key: Option<String>,
+
+    /// formats.<pattern> overrides how files are merged. The pattern is either an extension or a glob matched
+    /// against the path of the file within the modpack, and the value is json, cfg, ini or none
+    /// none means the files are merged as a whole rather than value by value. Later patterns take priority
+    pub formats: Option<IndexMap<String, String>>,
+
+    /// The mount point that corresponds to the game root (the folder containing Stalker2 and Engine)
+    /// Mount points of the mods' paks are resolved against it and the modpack is mounted at it. Defaults to "../../../"
+    pub game_root: Option<String>,
+
+    /// The .pak version of the modpack, see [`PakVersion`]. Defaults to V8B
+    pub pak_version: Option<PakVersion>,
+
+    /// The mount point of the modpack. Every file in the modpack must be inside of it
+    /// Defaults to the mount point of the mods when they all share one, otherwise to game_root
+    pub mount_point: Option<String>,
+
+    /// How the files in the modpack are compressed, none or zlib. Defaults to none
+    pub compression: Option<Compression>,
+
+    /// What goes into the modpack, all or merged_only. Defaults to all
+    /// merged_only only packs the files that more than one mod changed and the files of non-.pak mods, so the .pak mods
+    /// must stay installed alongside it
+    pub output_mode: Option<OutputMode>,
+
+    /// How the mods are installed in copy_to_dir, modpack or mod_paks. Defaults to modpack
+    /// mod_paks also installs the .pak mods themselves, so the modpack only has to contain the files that were merged
+    pub deploy: Option<DeployMode>,
+
+    /// profiles.<profile_name> allows you to build different modpacks from the same mods_dir
+    pub profiles: Option<IndexMap<String, UpmmProfile>>,
+}
+
+/// The mount point of the game root (the folder containing Stalker2 and Engine). Mount points are relative to the
+/// game's executable in Engine/Binaries/Win64
+pub const DEFAULT_GAME_ROOT: &str = "../../../";
+
+/// The .pak version of the modpack, set with pak_version
+/// repak reads every version, but its tests only check the paks it writes against ones made by the engine for these
+/// versions. V6 and V10 are left out, since a modpack the game can't mount is worse than a build that fails
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PakVersion {
+    V5,
+    V7,
+    V8A,
+    #[default]
+    V8B,
+    V9,
+    V11,
+}
+
+impl PakVersion {
+    /// Returns the version repak writes the modpack with
+    pub fn repak_version(self) -> repak::Version {
+        match self {
+            Self::V5 => repak::Version::V5,
+            Self::V7 => repak::Version::V7,
+            Self::V8A => repak::Version::V8A,
+            Self::V8B => repak::Version::V8B,
+            Self::V9 => repak::Version::V9,
+            Self::V11 => repak::Version::V11,
+        }
+    }
+}
+
+/// How the files in the modpack are compressed, set with compression
+/// Oodle, which the game's own paks use, isn't offered since repak can only read it
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// The files are stored as they are
+    #[default]
+    None,
+    /// zlib, which makes large modpacks a lot smaller
+    Zlib,
+}
+
+/// What goes into the modpack, set with output_mode
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    /// Every file of every mod
+    #[default]
+    All,
+    /// Only the files that more than one mod changed
+    MergedOnly,
+}
+
+/// How the mods are installed in copy_to_dir, set with deploy
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployMode {
+    /// Only the modpack
+    #[default]
+    Modpack,
+    /// The .pak mods, prefixed with their place in the merge order, and a modpack containing the merged files
+    ModPaks,
+}
+
+/// The config file that is created when none exists
+pub const DEFAULT_CONFIG_FILE: &str = r#"
+# The name of .pak file that is created
+name = "upmm_modpack"
+
+# The .pak file ends with _P unless patch_suffix is false, since Unreal only lets paks ending with _P override the game's
+# files. pak_prefix is prepended to it, i.e. "zzz_" so the modpack loads after the mods in copy_to_dir
+# The .pak file of this config is upmm_modpack_P.pak
+# pak_prefix = "zzz_"
+# patch_suffix = true
+
+# All directories in this config are relative to the location of this config file
+
+# The directory where all of the files are staged before being added to the .pak file
+# This directory will be a git repository so you can use git to look at the history of the files
+# Each input mod will contain it's own branch and merge commit
+# The directory is kept between builds so only the mods that changed, and the mods merged after them, are merged again
+staging_dir = "staging"
+
+# The directory where the mods are located
+# The directory can contain either:
+# - Directories that are essentially unpacked .pak files (assumes default mount point of "../../../")
+# - .pak files
+# - .pak/.utoc/.ucas triplets (the IoStore assets can't be merged, but overlaps between mods are reported)
+# - .zip archives containing any of the above. The archive's name is used as the mod's name
+mods_dir = "mods"
+
+# Mods in mods_dir that are skipped, so they don't have to be moved out of it. mods.<mod_name>.enabled = false does the same
+# disabled = ["zzzz_Grok_Boar-40pHP_P.pak"]
+
+# An optional directory to copy the finished pak file to
+# Setting it to your ~mods directory will mean you don't need to manually copy the .pak over each time you build
+# What was copied is recorded in <name>.install.json there, and the uninstall command removes it again
+# Below is the default Steam install location. Uncomment the line and edit to your install path
+# copy_to_dir = "C:\\Program Files (x86)\\Steam\\steamapps\\common\\S.T.A.L.K.E.R. 2 Heart of Chornobyl\\Stalker2\\Content\\Paks\\~mods"
+
+# The vanilla game files used as the base of the merge. Without a base, the first mod that touches a file is used as the base
+# base_paks_dir is a directory containing the game's .pak files and base_pak is a single .pak file that takes priority over base_paks_dir
+# Below is the default Steam install location. Uncomment the line and edit to your install path
+# base_paks_dir = "C:\\Program Files (x86)\\Steam\\steamapps\\common\\S.T.A.L.K.E.R. 2 Heart of Chornobyl\\Stalker2\\Content\\Paks"
+# base_pak = "pakchunk0-Windows.pak"
+
+# The AES key used to decrypt encrypted .pak files (including the game's own paks), as hex or base64
+# mods.<mod_name>.aes_key can be used to set a different key for a specific mod
+# aes_key = "0x0000000000000000000000000000000000000000000000000000000000000000"
+
+# The mount point of the game root (the folder containing Stalker2 and Engine), relative to the game's executable
+# The mount points of the mods' .pak files are resolved against it, files that end up outside of it are skipped
+# game_root = "../../../"
+
+# The .pak version of the modpack, one of V5, V7, V8A, V8B, V9 or V11. Defaults to V8B
+# pak_version = "V8B"
+
+# The mount point of the modpack, relative to the game's executable. Every file in the modpack must be inside of it
+# Defaults to the mods' mount point when they all use the same one, otherwise to game_root
+# mount_point = "../../../Stalker2/Content/"
+
+# How the files in the modpack are compressed, none or zlib. zlib makes large modpacks a lot smaller. Defaults to none
+# Oodle, which the game's own paks use, can be read from mods but not written
+# compression = "zlib"
+
+# What goes into the modpack, all or merged_only. Defaults to all, which packs every file of every mod
+# merged_only only packs the files that more than one mod changed, and the files of mods that aren't .pak files. The .pak
+# mods that changed other files must stay installed alongside the modpack, and are listed after the build
+# output_mode = "merged_only"
+
+# How the mods are installed in copy_to_dir, modpack or mod_paks. Defaults to modpack, which only installs the modpack
+# mod_paks also installs the .pak mods themselves, named <name>_<merge order>_<mod> so they load in the merge order and
+# before the modpack. The modpack then only contains the files that more than one mod changed and the files of the mods
+# that aren't .pak files. Binary files like .uasset can't be merged, so this keeps the modpack small
+# deploy = "mod_paks"
+
+# formats.<pattern> overrides how files are merged. The pattern is either an extension or a glob matched against the path of the file
+# within the modpack, and the value is json, cfg, ini or none (merge the whole file rather than value by value)
+# [formats]
+# "Stalker2/Content/GameLite/DLCGameData/**/*.json" = "none"
+
+# mods.<mod_name> allows you to set mod-specific options
+
+# mods.<mod_name>.priority sets the order in which the mods are merged into the final mod pack
+# Lower numbers are merged first, meaning changes in mod priority=2 will take priority over changes in mod priority=1
+# Without an explicit priority set, the mods priority is set via alphabetical order
+# For example, "a.pak", "b.pak", and "c.pak" will have priorities 0, 1, and 2 respectively
+# As such, it's recommended to set priorities above 1000 and below -1000 to ensure adding new mods won't affect existing priorities
+
+# [mods."zzzz_Grok_Boar-40pHP_P.pak"]
+# priority = -1000 # Merge this mod first
+# enabled = false # Skip this mod
+
+# Directory and .zip mods are added relative to the first folder in them that contains Stalker2, Engine, Content or GameLite
+# mods.<mod_name>.root sets the folder that corresponds to the game root (the folder containing Stalker2) instead
+# [mods."MyMod"]
+# root = "MyMod/Files"
+
+# profiles.<profile_name> builds a different modpack from the same mods_dir when running with --profile <profile_name>
+# A profile can override name, staging_dir, copy_to_dir and disabled, and profiles.<profile_name>.mods.<mod_name> overrides
+# the options of a mod. The modpack is named <name>_<profile_name> and staged in <staging_dir>_<profile_name> by default
+
+# [profiles.hardcore]
+# disabled = []
+# [profiles.hardcore.mods."zzzz_Grok_Boar-40pHP_P.pak"]
+# priority = 2000 # Merge this mod last
+"#;
+
+/// Parses an AES-256 key given as hex (optionally prefixed with 0x) or base64
+pub fn parse_aes_key(key: &str) -> Result<aes::Aes256> {
+    use aes::cipher::KeyInit;
+    use base64::Engine;
+
+    let key = key.trim();
+    let hex_key = key.strip_prefix("0x").unwrap_or(key);
+    let bytes = if hex_key.len() == 64 && hex_key.chars().all(|c| c.is_ascii_hexdigit()) {
+        hex::decode(hex_key)?
+    } else {
+        base64::engine::general_purpose::STANDARD
+            .decode(key)
+            .context("AES key must be 64 hex characters or base64")?
+    };
+
+    aes::Aes256::new_from_slice(&bytes)
+        .map_err(|_| anyhow::anyhow!("AES key must be 32 bytes, got {} bytes", bytes.len()))
+}
+
+impl UpmmConfig {
+    /// Reads and parses a config file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file '{}'", path.display()))
+            .context(ErrorKind::Config)?;
+
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file '{}'", path.display()))
+            .context(ErrorKind::Config)
+    }
+
+    /// Returns the config with the overrides of the given profile applied
+    pub fn with_profile(&self, profile_name: &str) -> Result<Self> {
+        let profile = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(profile_name))
+            .ok_or_else(|| {
+                let available: Vec<&str> = self
+                    .profiles
+                    .iter()
+                    .flat_map(|profiles| profiles.keys())
+                    .map(String::as_str)
+                    .collect();
+                anyhow::anyhow!(
+                    "Unknown profile '{}', the config has: {}",
+                    profile_name,
+                    if available.is_empty() {
+                        "no profiles".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                )
+            })
+            .context(ErrorKind::Config)?
+            .clone();
+
+        let mut config = self.clone();
+        config.name = profile
+            .name
+            .unwrap_or_else(|| format!("{}_{}", self.name, profile_name));
+        config.staging_dir = profile
+            .staging_dir
+            .unwrap_or_else(|| format!("{}_{}", self.staging_dir, profile_name));
+
+        if profile.copy_to_dir.is_some() {
+            config.copy_to_dir = profile.copy_to_dir;
+        }
+
+        if profile.disabled.is_some() {
+            config.disabled = profile.disabled;
+        }
+
+        for (mod_name, overrides) in profile.mods.unwrap_or_default() {
+            let mod_config = config
+                .mods
+                .get_or_insert_with(HashMap::new)
+                .entry(mod_name)
+                .or_default();
+
+            if overrides.priority.is_some() {
+                mod_config.priority = overrides.priority;
+            }
+            if overrides.aes_key.is_some() {
+                mod_config.aes_key = overrides.aes_key;
+            }
+            if overrides.enabled.is_some() {
+                mod_config.enabled = overrides.enabled;
+            }
+            if overrides.root.is_some() {
+                mod_config.root = overrides.root;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the file name of the modpack: pak_prefix, name and the _P suffix unless the name already ends with it
+    pub fn pak_file_name(&self) -> String {
+        let mut file_name = format!(
+            "{}{}",
+            self.pak_prefix.as_deref().unwrap_or_default(),
+            self.name
+        );
+
+        let has_suffix = file_name
+            .get(file_name.len().saturating_sub(2)..)
+            .is_some_and(|suffix| suffix.eq_ignore_ascii_case("_P"));
+        if self.patch_suffix.unwrap_or(true) && !has_suffix {
+            file_name.push_str("_P");
+        }
+
+        file_name + ".pak"
+    }
+
+    /// Returns the mount point of the game root
+    pub fn game_root(&self) -> &str {
+        self.game_root.as_deref().unwrap_or(DEFAULT_GAME_ROOT)
+    }
+
+    /// Returns the .pak version of the modpack
+    pub fn pak_version(&self) -> repak::Version {
+        self.pak_version.unwrap_or_default().repak_version()
+    }
+
+    /// Returns the compression of the files in the modpack, or None if they aren't compressed
+    pub fn compression(&self) -> Option<repak::Compression> {
+        match self.compression.unwrap_or_default() {
+            Compression::None => None,
+            Compression::Zlib => Some(repak::Compression::Zlib),
+        }
+    }
+
+    /// Returns what goes into the modpack
+    pub fn output_mode(&self) -> OutputMode {
+        self.output_mode.unwrap_or_default()
+    }
+
+    /// Returns how the mods are installed in copy_to_dir
+    pub fn deploy(&self) -> Result<DeployMode> {
+        let mode = self.deploy.unwrap_or_default();
+        if mode == DeployMode::ModPaks && self.copy_to_dir.is_none() {
+            return Err(anyhow::anyhow!(
+                "deploy = \"mod_paks\" installs the mods in copy_to_dir, which isn't set"
+            ))
+            .context(ErrorKind::Config);
+        }
+
+        Ok(mode)
+    }
+
+    /// Returns the options for the given mod, if any are set
+    pub fn mod_config(&self, mod_name: &str) -> Option<&UpmmModConfig> {
+        self.mods.as_ref().and_then(|mods| mods.get(mod_name))
+    }
+
+    /// Returns the AES key for the given mod, falling back to the top-level aes_key
+    pub fn mod_aes_key(&self, mod_name: &str) -> Result<Option<aes::Aes256>> {
+        let key = self
+            .mod_config(mod_name)
+            .and_then(|v| v.aes_key.as_ref())
+            .or(self.aes_key.as_ref());
+
+        key.map(|key| {
+            parse_aes_key(key)
+                .with_context(|| format!("Invalid aes_key for {}", mod_name))
+                .context(ErrorKind::Config)
+        })
+        .transpose()
+    }
+
+    /// Returns the AES key used for the base paks
+    pub fn base_aes_key(&self) -> Result<Option<aes::Aes256>> {
+        self.aes_key
+            .as_deref()
+            .map(parse_aes_key)
+            .transpose()
+            .context("Invalid aes_key")
+            .context(ErrorKind::Config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(test: &str, contents: &str) -> Result<UpmmConfig> {
+        let path =
+            std::env::temp_dir().join(format!("upmm_config_{}_{}.toml", test, std::process::id()));
+        let base = "name = \"modpack\"\nstaging_dir = \"staging\"\nmods_dir = \"mods\"\n";
+        std::fs::write(&path, format!("{}{}", base, contents)).unwrap();
+        let config = UpmmConfig::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn options_are_parsed_into_their_values() {
+        let config = parse(
+            "valid",
+            "pak_version = \"V11\"\ncompression = \"zlib\"\noutput_mode = \"merged_only\"\ndeploy = \"mod_paks\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.pak_version, Some(PakVersion::V11));
+        assert_eq!(config.compression(), Some(repak::Compression::Zlib));
+        assert_eq!(config.output_mode(), OutputMode::MergedOnly);
+        assert_eq!(config.deploy, Some(DeployMode::ModPaks));
+
+        let config = parse("defaults", "").unwrap();
+        assert_eq!(config.pak_version, None);
+        assert_eq!(config.compression(), None);
+        assert_eq!(config.output_mode(), OutputMode::All);
+        assert_eq!(config.deploy().unwrap(), DeployMode::Modpack);
+    }
+
+    /// The example config is the default config with its own name, mods and profiles added, so the option docs in
+    /// both stay the same
+    #[test]
+    fn example_config_matches_the_default_config() {
+        let example = include_str!("../example/config.toml");
+        let mut example_lines = example.lines().filter(|line| !line.trim().is_empty());
+
+        for line in DEFAULT_CONFIG_FILE
+            .replace("upmm_modpack", "example_modpack")
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+        {
+            assert!(
+                example_lines.any(|example_line| example_line == line),
+                "example/config.toml is missing or reorders this line of DEFAULT_CONFIG_FILE:\n{}",
+                line
+            );
+        }
+    }
+
+    /// Invalid values fail when the config is read, so every command rejects them rather than only the ones that use
+    /// the value
+    #[test]
+    fn invalid_options_fail_to_parse() {
+        for contents in [
+            "pak_version = \"V6\"",
+            "pak_version = \"V12\"",
+            "compression = \"oodle\"",
+            "output_mode = \"some\"",
+            "deploy = \"mods\"",
+        ] {
+            let error = parse("invalid", contents).unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<ErrorKind>(),
+                Some(&ErrorKind::Config),
+                "{}",
+                contents
+            );
+        }
+    }
+}
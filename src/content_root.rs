@@ -0,0 +1,192 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::error::ErrorKind;
+
+/// The folders in the root of the game, which is where the default mount point of "../../../" points to
+const GAME_ROOT_DIRS: [&str; 2] = ["Stalker2", "Engine"];
+
+/// Folders that mods often start from instead of the root of the game, along with where they are in the game
+const NESTED_DIRS: [(&str, &str); 2] = [("Content", "Stalker2"), ("GameLite", "Stalker2/Content")];
+
+/// How many folders deep into a mod to look for the game's folders
+const MAX_DEPTH: usize = 3;
+
+/// The directory of a loose mod that matches a directory in the game
+#[derive(Debug, Clone)]
+pub(crate) struct ContentRoot {
+    /// The directory of the mod the root was found in
+    pub mod_dir: PathBuf,
+    pub dir: PathBuf,
+    /// Where `dir` is in the game, relative to the game root
+    pub prefix: PathBuf,
+}
+
+impl ContentRoot {
+    /// Pairs each file with where it goes, relative to the staging directory. Files outside the root are skipped
+    pub fn map_files(
+        &self,
+        mod_name: &str,
+        files: impl IntoIterator<Item = PathBuf>,
+    ) -> Vec<(PathBuf, PathBuf)> {
+        files
+            .into_iter()
+            .filter_map(|file| match file.strip_prefix(&self.dir) {
+                Ok(relative_path) => {
+                    let staged_path = self.prefix.join(relative_path);
+                    Some((file, staged_path))
+                }
+                Err(_) => {
+                    log::warn!(
+                        "{}: Skipping {}, it's outside of the content root {}",
+                        mod_name,
+                        self.display(&file).display(),
+                        self.display(&self.dir).display()
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Paths are shown relative to the mod, since archives are extracted into a temporary directory
+    fn display<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.mod_dir).unwrap_or(path)
+    }
+}
+
+/// Returns the subdirectories of `dir` sorted by name, ignoring .git
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory '{}'", dir.display()))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && path.file_name().is_some_and(|name| name != ".git"))
+        .collect();
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+fn dir_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// Finds the directory of a mod that matches a directory in the game. With `root_override` (mods.<mod_name>.root) that
+/// directory is used as the game root. Otherwise the shallowest directory containing one of the game's folders is used,
+/// falling back to the mod directory itself
+pub(crate) fn find(mod_name: &str, dir: &Path, root_override: Option<&str>) -> Result<ContentRoot> {
+    if let Some(root) = root_override {
+        // Like the entries of .pak files, the root must not lead outside of the mod
+        let inside = Path::new(root)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !inside {
+            return Err(anyhow!(
+                "root '{}' of {} must be a folder inside of the mod",
+                root,
+                mod_name
+            ))
+            .context(ErrorKind::Config);
+        }
+
+        let root_dir = dir.join(root);
+        if !root_dir.is_dir() {
+            return Err(anyhow!(
+                "root '{}' of {} is not a directory",
+                root,
+                mod_name
+            ))
+            .context(ErrorKind::Config);
+        }
+
+        return Ok(ContentRoot {
+            mod_dir: dir.to_path_buf(),
+            dir: root_dir,
+            prefix: PathBuf::new(),
+        });
+    }
+
+    let mut candidates = vec![dir.to_path_buf()];
+    // The candidates of each pass are one folder deeper, and their children are checked for the game's folders
+    for _ in 0..MAX_DEPTH {
+        let mut next = Vec::new();
+
+        for candidate in candidates {
+            let children = subdirs(&candidate)?;
+
+            let found = if children.iter().any(|child| {
+                GAME_ROOT_DIRS
+                    .iter()
+                    .any(|name| dir_name(child).eq_ignore_ascii_case(name))
+            }) {
+                Some(PathBuf::new())
+            } else {
+                NESTED_DIRS
+                    .iter()
+                    .find(|(name, _)| {
+                        children
+                            .iter()
+                            .any(|child| dir_name(child).eq_ignore_ascii_case(name))
+                    })
+                    .map(|(_, prefix)| PathBuf::from(prefix))
+            };
+
+            if let Some(prefix) = found {
+                let root = ContentRoot {
+                    mod_dir: dir.to_path_buf(),
+                    dir: candidate,
+                    prefix,
+                };
+
+                if root.dir != dir || !root.prefix.as_os_str().is_empty() {
+                    let relative_dir = root.display(&root.dir);
+                    log::info!(
+                        "{}: Adding the files in {} to {}",
+                        mod_name,
+                        if relative_dir.as_os_str().is_empty() {
+                            mod_name.to_string()
+                        } else {
+                            relative_dir.display().to_string()
+                        },
+                        if root.prefix.as_os_str().is_empty() {
+                            "the game root".to_string()
+                        } else {
+                            root.prefix.display().to_string()
+                        }
+                    );
+                }
+
+                return Ok(root);
+            }
+
+            next.extend(children);
+        }
+
+        candidates = next;
+    }
+
+    Ok(ContentRoot {
+        mod_dir: dir.to_path_buf(),
+        dir: dir.to_path_buf(),
+        prefix: PathBuf::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_overrides_outside_of_the_mod_are_rejected() {
+        let mod_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example/mods/blind_dog");
+
+        for root in ["..", "../abc", "Stalker2/../..", "/tmp", "."] {
+            assert!(find("blind_dog", &mod_dir, Some(root)).is_err(), "{}", root);
+        }
+        assert!(find("blind_dog", &mod_dir, Some("Stalker2")).is_ok());
+    }
+}
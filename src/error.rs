@@ -0,0 +1,23 @@
+use std::fmt::Display;
+
+/// What kind of step of the build failed. It's attached to errors as context, so callers can tell failures apart with
+/// `error.downcast_ref::<ErrorKind>()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The config is missing, can't be parsed or contains invalid values
+    Config,
+    /// A mod or base pak couldn't be read or extracted into the staging directory
+    Extraction,
+    /// The modpack couldn't be written or copied to copy_to_dir
+    PakWrite,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config => write!(f, "Invalid config"),
+            Self::Extraction => write!(f, "Failed to extract mods"),
+            Self::PakWrite => write!(f, "Failed to write the modpack"),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use indexmap::IndexMap;
+
+use crate::{
+    merge::{self, ValueConflict},
+    stalker2_cfg::{self, Stalker2Cfg},
+    unreal_ini::{self, UnrealIni},
+};
+
+/// A file format that can be merged value by value
+///
+/// Implement this to add support for a new format and register it with a [`FormatRegistry`]
+pub trait FormatMerger: Send + Sync {
+    /// The parsed file
+    type Document;
+
+    /// The name of the format used in log messages, i.e. "cfg"
+    fn name(&self) -> &str;
+
+    /// Parses the contents of the file at the given path
+    fn parse(&self, path: &str, content: &str) -> Result<Self::Document>;
+
+    /// Prints the document back into the contents of a file
+    fn print(&self, document: &Self::Document) -> String;
+
+    /// Three-way merge of the documents. When both sides change the same value, theirs wins and
+    /// the conflict is returned alongside the merged document
+    fn merge(
+        &self,
+        base: &Self::Document,
+        ours: &Self::Document,
+        theirs: &Self::Document,
+    ) -> Result<(Self::Document, Vec<ValueConflict>)>;
+
+    /// Breaks the document down into its individual values, keyed by the same paths used in
+    /// [`ValueConflict::path`]
+    fn values_by_path(&self, document: &Self::Document) -> IndexMap<String, String>;
+
+    /// Rewrites a file into the form it's staged in, so formatting differences between mods don't
+    /// show up as changes. Parses and prints the file by default
+    fn normalize(&self, path: &str, content: &str) -> Result<String> {
+        Ok(self.print(&self.parse(path, content)?))
+    }
+}
+
+/// An object safe version of [`FormatMerger`] that works on the contents of files. It's
+/// implemented for every [`FormatMerger`] so mergers of different formats can be stored together
+pub trait DynFormatMerger: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn normalize_string(&self, path: &str, content: &str) -> Result<String>;
+
+    fn merge_strings(
+        &self,
+        path: &str,
+        base: &str,
+        ours: &str,
+        theirs: &str,
+    ) -> Result<(String, Vec<ValueConflict>)>;
+
+    fn string_values_by_path(&self, path: &str, content: &str) -> Result<IndexMap<String, String>>;
+}
+
+impl<T: FormatMerger> DynFormatMerger for T {
+    fn name(&self) -> &str {
+        FormatMerger::name(self)
+    }
+
+    fn normalize_string(&self, path: &str, content: &str) -> Result<String> {
+        self.normalize(path, content)
+    }
+
+    fn merge_strings(
+        &self,
+        path: &str,
+        base: &str,
+        ours: &str,
+        theirs: &str,
+    ) -> Result<(String, Vec<ValueConflict>)> {
+        let base = self.parse(path, base)?;
+        let ours = self.parse(path, ours)?;
+        let theirs = self.parse(path, theirs)?;
+
+        let (merged, conflicts) = self.merge(&base, &ours, &theirs)?;
+        Ok((self.print(&merged), conflicts))
+    }
+
+    fn string_values_by_path(&self, path: &str, content: &str) -> Result<IndexMap<String, String>> {
+        Ok(self.values_by_path(&self.parse(path, content)?))
+    }
+}
+
+pub struct JsonMerger;
+
+impl FormatMerger for JsonMerger {
+    type Document = serde_json::Value;
+
+    fn name(&self) -> &str {
+        "JSON"
+    }
+
+    fn parse(&self, _path: &str, content: &str) -> Result<Self::Document> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    fn print(&self, document: &Self::Document) -> String {
+        serde_json::to_string_pretty(document).expect("JSON values can always be printed") + "\n"
+    }
+
+    fn merge(
+        &self,
+        base: &Self::Document,
+        ours: &Self::Document,
+        theirs: &Self::Document,
+    ) -> Result<(Self::Document, Vec<ValueConflict>)> {
+        merge::merge_json_values(base, ours, theirs)
+    }
+
+    fn values_by_path(&self, document: &Self::Document) -> IndexMap<String, String> {
+        merge::json_values_by_pointer(document)
+    }
+}
+
+pub struct CfgMerger;
+
+impl FormatMerger for CfgMerger {
+    type Document = Stalker2Cfg;
+
+    fn name(&self) -> &str {
+        "cfg"
+    }
+
+    fn parse(&self, path: &str, content: &str) -> Result<Self::Document> {
+        Stalker2Cfg::from_str(path.to_string(), content)
+    }
+
+    fn print(&self, document: &Self::Document) -> String {
+        document.to_string()
+    }
+
+    fn merge(
+        &self,
+        base: &Self::Document,
+        ours: &Self::Document,
+        theirs: &Self::Document,
+    ) -> Result<(Self::Document, Vec<ValueConflict>)> {
+        stalker2_cfg::merge_cfg_structs(base, ours, theirs)
+    }
+
+    fn values_by_path(&self, document: &Self::Document) -> IndexMap<String, String> {
+        document.values_by_path()
+    }
+}
+
+pub struct IniMerger;
+
+impl FormatMerger for IniMerger {
+    type Document = UnrealIni;
+
+    fn name(&self) -> &str {
+        "ini"
+    }
+
+    fn parse(&self, _path: &str, content: &str) -> Result<Self::Document> {
+        Ok(UnrealIni::parse(content))
+    }
+
+    fn print(&self, document: &Self::Document) -> String {
+        document.to_string() + "\n"
+    }
+
+    fn merge(
+        &self,
+        base: &Self::Document,
+        ours: &Self::Document,
+        theirs: &Self::Document,
+    ) -> Result<(Self::Document, Vec<ValueConflict>)> {
+        unreal_ini::merge_unreal_inis(base, ours, theirs)
+    }
+
+    fn values_by_path(&self, document: &Self::Document) -> IndexMap<String, String> {
+        document.values_by_path()
+    }
+
+    /// Printing an ini drops its comments, so ini files are only rewritten when they are merged
+    fn normalize(&self, _path: &str, content: &str) -> Result<String> {
+        Ok(content.to_string())
+    }
+}
+
+/// Which files a merger is used for
+#[derive(Clone, Debug)]
+enum FormatPattern {
+    /// Matches the extension of the file, case-insensitively
+    Extension(String),
+    /// Matches the path of the file within the modpack, case-insensitively
+    Glob(glob::Pattern),
+}
+
+impl FormatPattern {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            Self::Extension(ext) => Path::new(path)
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.eq_ignore_ascii_case(ext)),
+            Self::Glob(pattern) => pattern.matches_with(
+                path,
+                glob::MatchOptions {
+                    case_sensitive: false,
+                    require_literal_separator: false,
+                    require_literal_leading_dot: false,
+                },
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct FormatEntry {
+    pattern: FormatPattern,
+    /// None means files matching the pattern are not merged value by value
+    merger: Option<Arc<dyn DynFormatMerger>>,
+}
+
+/// The mergers used for each kind of file. When several patterns match a file, the one registered
+/// last is used, so later registrations override earlier ones
+#[derive(Clone)]
+pub struct FormatRegistry {
+    entries: Vec<FormatEntry>,
+}
+
+impl Default for FormatRegistry {
+    /// The built-in json, cfg and ini mergers
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        for name in ["json", "cfg", "ini"] {
+            registry.entries.push(FormatEntry {
+                pattern: FormatPattern::Extension(name.to_string()),
+                merger: Self::builtin(name),
+            });
+        }
+
+        registry
+    }
+}
+
+impl Debug for FormatRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| {
+                (
+                    &entry.pattern,
+                    entry
+                        .merger
+                        .as_ref()
+                        .map(|merger| merger.name().to_string()),
+                )
+            }))
+            .finish()
+    }
+}
+
+impl FormatRegistry {
+    /// A registry without any mergers, so every file is merged as a whole
+    pub fn empty() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns one of the built-in mergers by name: json, cfg or ini
+    pub fn builtin(name: &str) -> Option<Arc<dyn DynFormatMerger>> {
+        match name {
+            "json" => Some(Arc::new(JsonMerger)),
+            "cfg" => Some(Arc::new(CfgMerger)),
+            "ini" => Some(Arc::new(IniMerger)),
+            _ => None,
+        }
+    }
+
+    /// Uses the merger for files with the given extension (without the leading dot)
+    pub fn register_extension(&mut self, extension: &str, merger: Arc<dyn DynFormatMerger>) {
+        self.entries.push(FormatEntry {
+            pattern: FormatPattern::Extension(extension.trim_start_matches('.').to_string()),
+            merger: Some(merger),
+        });
+    }
+
+    /// Uses the merger for files whose path within the modpack matches the glob, i.e.
+    /// `Stalker2/Content/**/DataTables/*.json`
+    pub fn register_glob(&mut self, pattern: &str, merger: Arc<dyn DynFormatMerger>) -> Result<()> {
+        self.entries.push(FormatEntry {
+            pattern: FormatPattern::Glob(parse_glob(pattern)?),
+            merger: Some(merger),
+        });
+
+        Ok(())
+    }
+
+    /// Merges files whose path matches the glob as a whole, even if a merger is registered for their extension
+    pub fn ignore_glob(&mut self, pattern: &str) -> Result<()> {
+        self.entries.push(FormatEntry {
+            pattern: FormatPattern::Glob(parse_glob(pattern)?),
+            merger: None,
+        });
+
+        Ok(())
+    }
+
+    /// Applies the formats table of the config. Keys are globs (or bare extensions) and values are
+    /// the name of a built-in merger or "none"
+    pub fn apply_overrides(&mut self, formats: &IndexMap<String, String>) -> Result<()> {
+        for (pattern, format) in formats {
+            let merger = match format.as_str() {
+                "none" => None,
+                name => Some(Self::builtin(name).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown format '{}' for '{}', expected json, cfg, ini or none",
+                        name,
+                        pattern
+                    )
+                })?),
+            };
+
+            let is_extension = !pattern.contains(['*', '?', '[', '/']);
+            let pattern = if is_extension {
+                FormatPattern::Extension(pattern.trim_start_matches('.').to_string())
+            } else {
+                FormatPattern::Glob(parse_glob(pattern)?)
+            };
+
+            self.entries.push(FormatEntry { pattern, merger });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the merger for the file at the given path within the modpack, if there is one
+    pub fn get(&self, path: &str) -> Option<&dyn DynFormatMerger> {
+        let path = path.replace('\\', "/");
+
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.pattern.matches(&path))
+            .and_then(|entry| entry.merger.as_deref())
+    }
+
+    /// Rewrites a file into the form it's staged in. Files without a merger are left untouched
+    pub fn normalize(&self, path: &Path, content: &[u8]) -> Result<Vec<u8>> {
+        let Some(merger) = path.to_str().and_then(|p| self.get(p)) else {
+            return Ok(content.to_vec());
+        };
+
+        let str_content = std::str::from_utf8(content)
+            .context(format!("non-utf8 bytes found in {}", path.display()))?;
+
+        Ok(merger
+            .normalize_string(&path.to_string_lossy(), str_content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?
+            .into_bytes())
+    }
+}
+
+fn parse_glob(pattern: &str) -> Result<glob::Pattern> {
+    glob::Pattern::new(pattern).with_context(|| format!("Invalid glob '{}'", pattern))
+}
@@ -1,9 +1,16 @@
-use git2::{Error, FileFavor, MergeOptions, Repository};
-use std::{io::Read, path::Path};
+use git2::{Error, FileFavor, MergeOptions, Oid, Repository};
+use std::path::Path;
 
-use crate::{merge, stalker2_cfg, unreal_ini};
-use stalker2_cfg::Stalker2Cfg;
-use unreal_ini::UnrealIni;
+use crate::{formats::FormatRegistry, merge::ValueConflict};
+
+/// The name every commit in the staging repository is signed with
+const SIGNATURE_NAME: &str = "Strelok";
+
+/// The signature of every commit in the staging repository. It's fixed rather than read from the git config, which most
+/// players don't have
+fn signature() -> Result<git2::Signature<'static>, Error> {
+    git2::Signature::now(SIGNATURE_NAME, "The Zone")
+}
 
 pub fn checkout_branch(repo: &Repository, branch_name: &str) -> Result<(), Error> {
     let branch_name = &normalize_git_ref(branch_name);
@@ -45,6 +52,10 @@ pub fn checkout_branch(repo: &Repository, branch_name: &str) -> Result<(), Error
     Ok(())
 }
 
+/// The branch that holds every file as it was before any mod changed it. Mod branches are created from it, so the
+/// vanilla version of a file (or the version of the first mod that added it) is always the merge base
+pub const ORIGINALS_BRANCH: &str = "upmm/originals";
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MergeStrategy {
     Custom,    // Use custom merge logic
@@ -52,14 +63,29 @@ pub enum MergeStrategy {
     Overwrite, // Overwrite our version with theirs
 }
 
+/// The conflicts found in a single file while merging a branch
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FileConflicts {
+    /// The path of the file relative to the repository root
+    pub path: String,
+    /// The branch that was merged in. Its values are the ones that were kept
+    pub branch: String,
+    /// The values that both sides changed
+    pub conflicts: Vec<ValueConflict>,
+    /// True if the file could not be merged value by value, so it was merged line by line instead, keeping the branch's
+    /// lines where both sides changed the same lines
+    pub overwritten: bool,
+}
+
 pub fn merge_branch(
     repo: &Repository,
     from_branch: &str,
     strategy: MergeStrategy,
-) -> Result<(), Error> {
+    formats: &FormatRegistry,
+) -> Result<Vec<FileConflicts>, Error> {
     let from_branch = &normalize_git_ref(from_branch);
 
-    println!("{}: Merging files", from_branch);
+    log::info!("{}: Merging files", from_branch);
 
     // Get the source branch's commit
     let from = repo.find_branch(from_branch, git2::BranchType::Local)?;
@@ -83,15 +109,14 @@ pub fn merge_branch(
     }
 
     // Perform the merge with options
-    repo.merge(&[&annotated_commit], Some(&mut merge_opts), None)
-        .expect("Failed to perform merge");
+    repo.merge(&[&annotated_commit], Some(&mut merge_opts), None)?;
 
     // Get conflicted files
-    let mut unhandled_conflicts = false;
+    let mut file_conflicts: Vec<FileConflicts> = Vec::new();
     let index = repo.index()?;
 
     if index.conflicts()?.count() == 0 {
-        println!("{}: All files merged without conflicts", from_branch);
+        log::info!("{}: All files merged without conflicts", from_branch);
     }
 
     for entry in index.conflicts()? {
@@ -106,37 +131,55 @@ pub fn merge_branch(
         let their_id = conflict.their.as_ref().map(|e| e.id);
 
         if let (Some(path), Some(our_id), Some(their_id)) = (path, our_id, their_id) {
-            let ancestor_id = conflict
-                .ancestor
-                .as_ref()
-                .map(|e| e.id)
-                .expect("No ancestor");
+            // Files that both sides added have no ancestor, they are merged against an empty file
+            let ancestor_id = conflict.ancestor.as_ref().map(|e| e.id);
 
             // Handle potential error from merge conflict resolution
-            if let Err(_e) =
-                handle_merge_conflict(repo, &path, ancestor_id, our_id, their_id, &from_branch)
-            {
-                // Overwrite the current file content with ours
-                let our_blob = repo.find_blob(our_id)?;
-                let workdir = repo.workdir().expect("Repository has no working directory");
-                let full_path = workdir.join(&path);
-                std::fs::write(&full_path, our_blob.content()).map_err(|e| {
-                    Error::from_str(&format!("Failed to write restored file {}: {}", path, e))
-                })?;
-
-                let mut index = repo.index()?;
-                index.add_path(Path::new(&path))?;
-                index.write()?;
-
-                unhandled_conflicts = true;
-
-                continue;
+            match handle_merge_conflict(
+                repo,
+                &path,
+                ancestor_id,
+                our_id,
+                their_id,
+                from_branch,
+                formats,
+            ) {
+                Ok(conflicts) => {
+                    if !conflicts.is_empty() {
+                        file_conflicts.push(FileConflicts {
+                            path,
+                            branch: from_branch.clone(),
+                            conflicts,
+                            overwritten: false,
+                        });
+                    }
+                }
+                Err(_e) => {
+                    // Overwrite the current file content with ours
+                    let our_blob = repo.find_blob(our_id)?;
+                    let workdir = repo.workdir().expect("Repository has no working directory");
+                    let full_path = workdir.join(&path);
+                    std::fs::write(&full_path, our_blob.content()).map_err(|e| {
+                        Error::from_str(&format!("Failed to write restored file {}: {}", path, e))
+                    })?;
+
+                    let mut index = repo.index()?;
+                    index.add_path(Path::new(&path))?;
+                    index.write()?;
+
+                    file_conflicts.push(FileConflicts {
+                        path,
+                        branch: from_branch.clone(),
+                        conflicts: Vec::new(),
+                        overwritten: true,
+                    });
+                }
             }
         }
     }
 
     // Create the merge commit
-    let sig = repo.signature()?;
+    let sig = signature()?;
     let message = format!("Merge branch '{}'", from_branch);
     let tree = repo.index()?.write_tree()?;
     let tree = repo.find_tree(tree)?;
@@ -147,102 +190,63 @@ pub fn merge_branch(
     // Clean up the merge state
     repo.cleanup_state()?;
 
-    if unhandled_conflicts {
-        if strategy == MergeStrategy::Theirs {
-            merge_branch(repo, from_branch, MergeStrategy::Overwrite)?;
+    if file_conflicts.iter().any(|c| c.overwritten) {
+        let next_strategy = if strategy == MergeStrategy::Theirs {
+            MergeStrategy::Overwrite
         } else {
-            merge_branch(repo, from_branch, MergeStrategy::Theirs)?;
-        }
+            MergeStrategy::Theirs
+        };
+
+        file_conflicts.extend(merge_branch(repo, from_branch, next_strategy, formats)?);
     }
 
-    Ok(())
+    Ok(file_conflicts)
 }
 
 fn handle_merge_conflict(
     repo: &Repository,
     path: &str,
-    base_id: git2::Oid,
+    base_id: Option<git2::Oid>,
     our_id: git2::Oid,
     their_id: git2::Oid,
     mod_name: &str,
-) -> anyhow::Result<()> {
-    let base_blob = repo.find_blob(base_id)?;
+    formats: &FormatRegistry,
+) -> anyhow::Result<Vec<ValueConflict>> {
+    let base_blob = base_id.map(|id| repo.find_blob(id)).transpose()?;
     let our_blob = repo.find_blob(our_id)?;
     let their_blob = repo.find_blob(their_id)?;
 
-    let mut base_buf = String::new();
-    base_blob
-        .content()
-        .read_to_string(&mut base_buf)
-        .expect("Failed to read base blob");
-
-    let mut our_buf = String::new();
-    our_blob
-        .content()
-        .read_to_string(&mut our_buf)
-        .expect("Failed to read our blob");
-
-    let mut their_buf = String::new();
-    their_blob
-        .content()
-        .read_to_string(&mut their_buf)
-        .expect("Failed to read their blob");
-
-    // Helper function to write and stage merged content
-    fn write_and_stage(repo: &Repository, path: &str, content: String) -> Result<(), Error> {
-        let workdir = repo.workdir().expect("Repository has no working directory");
-        let full_path = workdir.join(path);
-
-        // Write the merged content to the file
-        std::fs::write(&full_path, content + "\n").map_err(|e| {
-            Error::from_str(&format!(
-                "Failed to write merged content to {}: {}",
-                path, e
-            ))
-        })?;
-
-        // Stage the merged file
-        let mut index = repo.index()?;
-        index.add_path(Path::new(path))?;
-        index.write()?;
-
-        Ok(())
-    }
-
-    if path.ends_with(".json") {
-        let merged = merge::merge_json_strings(&base_buf, &our_buf, &their_buf)
-            .expect("Failed to merge JSON");
-
-        println!("{}: Merged JSON values in {}", mod_name, path);
-        return Ok(write_and_stage(repo, path, merged)?);
-    }
-
-    if path.ends_with(".cfg") {
-        let base_cfg = Stalker2Cfg::from_str(path.to_string(), &base_buf)?;
-        let our_cfg = Stalker2Cfg::from_str(path.to_string(), &our_buf)?;
-        let their_cfg = Stalker2Cfg::from_str(path.to_string(), &their_buf)?;
+    let base_buf = match &base_blob {
+        Some(blob) => std::str::from_utf8(blob.content())?,
+        None => "",
+    };
+    let our_buf = std::str::from_utf8(our_blob.content())?;
+    let their_buf = std::str::from_utf8(their_blob.content())?;
 
-        let merged_cfg = stalker2_cfg::merge_cfg_structs(&base_cfg, &our_cfg, &their_cfg)?;
+    let merger = formats
+        .get(path)
+        .ok_or_else(|| anyhow::anyhow!("Failed to resolve conflict for file: {}", path))?;
 
-        println!("{}: Merged cfg values in {}", mod_name, path);
-        return Ok(write_and_stage(repo, path, merged_cfg.to_string())?);
-    }
+    let (merged, conflicts) = merger.merge_strings(path, base_buf, our_buf, their_buf)?;
 
-    if path.ends_with(".ini") {
-        let base_ini = UnrealIni::from_str(&base_buf);
-        let our_ini = UnrealIni::from_str(&our_buf);
-        let their_ini = UnrealIni::from_str(&their_buf);
+    let workdir = repo.workdir().expect("Repository has no working directory");
+    let full_path = workdir.join(path);
 
-        let merged_ini = unreal_ini::merge_unreal_inis(&base_ini, &our_ini, &their_ini)?;
+    // Write the merged content to the file
+    std::fs::write(&full_path, merged).map_err(|e| {
+        Error::from_str(&format!(
+            "Failed to write merged content to {}: {}",
+            path, e
+        ))
+    })?;
 
-        println!("{}: Merged ini values in {}", mod_name, path);
-        return Ok(write_and_stage(repo, path, merged_ini.to_string())?);
-    }
+    // Stage the merged file
+    let mut index = repo.index()?;
+    index.add_path(Path::new(path))?;
+    index.write()?;
 
-    Err(anyhow::anyhow!(
-        "Failed to resolve conflict for file: {}",
-        path
-    ))
+    log::info!("{}: Merged {} values in {}", mod_name, merger.name(), path);
+    Ok(conflicts)
 }
 
 pub fn commit_files(repo: &Repository, message: &str, only_new: bool) -> Result<(), Error> {
@@ -251,7 +255,7 @@ pub fn commit_files(repo: &Repository, message: &str, only_new: bool) -> Result<
 
     // Add only untracked files
     let mut cb = |path: &Path, _matched_pathspec: &[u8]| {
-        if !only_new || repo.status_file(path).map_or(false, |s| s.is_wt_new()) {
+        if !only_new || repo.status_file(path).is_ok_and(|s| s.is_wt_new()) {
             files_to_commit = true;
             0
         } else {
@@ -276,7 +280,7 @@ pub fn commit_files(repo: &Repository, message: &str, only_new: bool) -> Result<
     let parent_commit = head.peel_to_commit()?;
 
     // Create the commit
-    let signature = git2::Signature::now("Strelok", "The Zone")?;
+    let signature = signature()?;
     repo.commit(
         Some("HEAD"),
         &signature,
@@ -289,6 +293,154 @@ pub fn commit_files(repo: &Repository, message: &str, only_new: bool) -> Result<
     Ok(())
 }
 
+/// Returns the paths of the files that differ between two commits
+pub fn changed_files(
+    repo: &Repository,
+    before: git2::Oid,
+    after: git2::Oid,
+) -> Result<Vec<String>, Error> {
+    if before == after {
+        return Ok(Vec::new());
+    }
+
+    let old_tree = repo.find_commit(before)?.tree()?;
+    let new_tree = repo.find_commit(after)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| {
+            delta
+                .new_file()
+                .path()
+                .or_else(|| delta.old_file().path())
+                .and_then(|p| p.to_str())
+                .map(|p| p.replace('\\', "/"))
+        })
+        .collect())
+}
+
+/// Merges a branch that only adds files into HEAD. Unlike merge_branch, the branch is kept as a parent of the merge
+/// commit so the commits on it become the merge base of the branches created from it
+pub fn merge_added_files(repo: &Repository, from_branch: &str) -> Result<(), Error> {
+    let from_branch = &normalize_git_ref(from_branch);
+    let from = repo.find_branch(from_branch, git2::BranchType::Local)?;
+    let from_commit = from.get().peel_to_commit()?;
+    let head_commit = repo.head()?.peel_to_commit()?;
+
+    // Nothing to do if the branch has already been merged
+    if head_commit.id() == from_commit.id()
+        || repo.graph_descendant_of(head_commit.id(), from_commit.id())?
+    {
+        return Ok(());
+    }
+
+    let mut index = repo.merge_commits(&head_commit, &from_commit, None)?;
+    if index.has_conflicts() {
+        return Err(Error::from_str(&format!(
+            "Failed to add the new files from {}",
+            from_branch
+        )));
+    }
+
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    let sig = signature()?;
+    let message = format!("Merge branch '{}'", from_branch);
+    repo.commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        &message,
+        &tree,
+        &[&head_commit, &from_commit],
+    )?;
+
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+
+    Ok(())
+}
+
+/// Points a branch at the given commit, creating the branch if it doesn't exist
+pub fn reset_branch(repo: &Repository, branch_name: &str, commit: Oid) -> Result<(), Error> {
+    let branch_name = &normalize_git_ref(branch_name);
+    let commit = repo.find_commit(commit)?;
+
+    // A branch can't be moved while it's checked out
+    repo.set_head_detached(commit.id())?;
+    repo.branch(branch_name, &commit, true)?;
+
+    Ok(())
+}
+
+/// Deletes a branch if it exists
+pub fn delete_branch(repo: &Repository, branch_name: &str) -> Result<(), Error> {
+    match repo.find_branch(&normalize_git_ref(branch_name), git2::BranchType::Local) {
+        Ok(mut branch) => branch.delete(),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks out a branch, discarding any changes and untracked files in the working directory
+pub fn force_checkout_branch(repo: &Repository, branch_name: &str) -> Result<(), Error> {
+    let branch_name = &normalize_git_ref(branch_name);
+    let branch = repo.find_branch(branch_name, git2::BranchType::Local)?;
+
+    repo.set_head(
+        branch
+            .get()
+            .name()
+            .expect("branch names are always valid utf-8 since we normalize them"),
+    )?;
+    repo.checkout_head(Some(
+        git2::build::CheckoutBuilder::new()
+            .force()
+            .remove_untracked(true),
+    ))?;
+
+    Ok(())
+}
+
+/// Writes the given files from a commit into the working directory
+pub fn restore_files(repo: &Repository, commit: Oid, files: &[String]) -> Result<(), Error> {
+    let tree = repo.find_commit(commit)?.tree()?;
+    let workdir = repo.workdir().expect("Repository has no working directory");
+
+    for file in files {
+        let blob = repo.find_blob(tree.get_path(Path::new(file))?.id())?;
+        let full_path = workdir.join(file);
+
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                Error::from_str(&format!("Failed to create directory for {}: {}", file, e))
+            })?;
+        }
+
+        std::fs::write(&full_path, blob.content())
+            .map_err(|e| Error::from_str(&format!("Failed to restore {}: {}", file, e)))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the changes between two commits as a patch
+pub fn diff_patch(repo: &Repository, before: Oid, after: Oid) -> Result<String, Error> {
+    let old_tree = repo.find_commit(before)?.tree()?;
+    let new_tree = repo.find_commit(after)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+    let mut patch = String::new();
+    diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+        if let '+' | '-' | ' ' = line.origin() {
+            patch.push(line.origin());
+        }
+        patch.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+
+    Ok(patch)
+}
+
 pub fn init_repository(path: &str) -> Result<Repository, Error> {
     // Initialize a new repository
     let repo = Repository::init(path)?;
@@ -303,7 +455,7 @@ pub fn init_repository(path: &str) -> Result<Repository, Error> {
         let tree = repo.find_tree(tree_id)?;
 
         // Create the initial commit
-        let signature = git2::Signature::now("Strelok", "The Zone")?;
+        let signature = signature()?;
         repo.commit(
             Some("HEAD"),
             &signature,
@@ -317,6 +469,29 @@ pub fn init_repository(path: &str) -> Result<Repository, Error> {
     Ok(repo)
 }
 
+/// Whether `path` is a repository created by [`init_repository`], found by its initial commit
+pub fn is_staging_repository(path: &Path) -> bool {
+    // Unlike discovering a repository, opening one doesn't look further up (i.e. at the user's own repository)
+    let Ok(repo) = Repository::open(path) else {
+        return false;
+    };
+
+    let initial = repo.revwalk().and_then(|mut walk| {
+        walk.push_head()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        walk.next().transpose()
+    });
+
+    match initial {
+        Ok(Some(oid)) => repo.find_commit(oid).is_ok_and(|commit| {
+            commit.parent_count() == 0
+                && commit.author().name() == Some(SIGNATURE_NAME)
+                && commit.message() == Some("Initial commit")
+        }),
+        _ => false,
+    }
+}
+
 pub fn normalize_git_ref(input: &str) -> String {
     let mut result = String::new();
     let mut last_char: Option<char> = None;
@@ -324,8 +499,7 @@ pub fn normalize_git_ref(input: &str) -> String {
     for c in input.chars() {
         let replacement = match c {
             // Replace ASCII control characters, space, ~, ^, :, ?, *, [, \
-            c if c < ' '
-                || c == ' '
+            c if c <= ' '
                 || c == '~'
                 || c == '^'
                 || c == ':'
@@ -339,7 +513,7 @@ pub fn normalize_git_ref(input: &str) -> String {
 
             // Handle slash specially
             '/' => {
-                if last_char.map_or(true, |lc| lc == '/') {
+                if last_char.is_none_or(|lc| lc == '/') {
                     // Skip consecutive slashes
                     continue;
                 }
@@ -356,11 +530,11 @@ pub fn normalize_git_ref(input: &str) -> String {
         // Special cases for dots
         if replacement == '.' {
             // Skip if it would create '..' sequence
-            if last_char.map_or(false, |lc| lc == '.') {
+            if last_char.is_some_and(|lc| lc == '.') {
                 continue;
             }
             // Skip if it would start a component with '.'
-            if last_char.map_or(false, |lc| lc == '/') {
+            if last_char.is_some_and(|lc| lc == '/') {
                 continue;
             }
         }
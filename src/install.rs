@@ -0,0 +1,358 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::build_state;
+
+/// A file the tool placed in copy_to_dir
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstalledFile {
+    /// The file name within copy_to_dir
+    pub name: String,
+    /// The git blob hash of the file, used to tell whether it was changed since it was installed
+    pub hash: String,
+    /// When the file was installed, in seconds since the Unix epoch
+    pub installed_at: u64,
+    /// The backups of the files that were there before, relative to copy_to_dir and oldest first. The first is the file
+    /// that was there before the tool, the others are files that were put in place of the installed file later on
+    pub backups: Vec<String>,
+}
+
+/// What the tool installed in copy_to_dir. It's written next to the installed files, so the next install and uninstall
+/// only touch the files the tool placed there
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InstallManifest {
+    /// The config file the installed files were built from
+    pub config: Option<PathBuf>,
+    /// When the files were installed, in seconds since the Unix epoch
+    pub installed_at: u64,
+    pub files: Vec<InstalledFile>,
+}
+
+impl InstallManifest {
+    /// The manifest of the modpack called `name` in `dir`
+    pub fn path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.install.json", name))
+    }
+
+    /// Returns None if nothing was installed
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read install manifest '{}'", path.display()))?;
+        let manifest = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse install manifest '{}'", path.display()))?;
+
+        Ok(Some(manifest))
+    }
+
+    /// Adds a file, replacing the entry of the same name
+    fn record(&mut self, file: InstalledFile) {
+        match self.files.iter_mut().find(|f| f.name == file.name) {
+            Some(entry) => *entry = file,
+            None => self.files.push(file),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write install manifest '{}'", path.display()))
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// The directory the replaced files are moved to, relative to copy_to_dir. Backups end with .bak rather than .pak, since
+/// the game also loads the paks in subdirectories
+fn backup_dir(name: &str) -> String {
+    format!("{}.backup", name)
+}
+
+/// Whether the file is still the one that was installed
+fn is_unchanged(dir: &Path, file: &InstalledFile) -> bool {
+    build_state::hash_file(&dir.join(&file.name)).is_ok_and(|hash| hash == file.hash)
+}
+
+/// Moves a file into the backup directory. Returns the path of the backup relative to `dir`
+fn back_up(dir: &Path, name: &str, file_name: &str) -> Result<String> {
+    let backup_dir = backup_dir(name);
+    fs::create_dir_all(dir.join(&backup_dir))
+        .with_context(|| format!("Failed to create directory '{}'", backup_dir))?;
+
+    let timestamp = now();
+    let mut backup = format!("{}/{}.{}.bak", backup_dir, file_name, timestamp);
+    let mut count = 1;
+    while dir.join(&backup).exists() {
+        backup = format!("{}/{}.{}.{}.bak", backup_dir, file_name, timestamp, count);
+        count += 1;
+    }
+
+    fs::rename(dir.join(file_name), dir.join(&backup))
+        .with_context(|| format!("Failed to back up '{}'", file_name))?;
+    log::info!("Backed up {} to {}", file_name, backup);
+
+    Ok(backup)
+}
+
+/// Removes an installed file and restores the newest file it replaced. Files that were changed since they were
+/// installed are left alone. Returns false if the file was left in place
+fn remove_installed(dir: &Path, file: &InstalledFile) -> Result<bool> {
+    let path = dir.join(&file.name);
+    if path.exists() {
+        if !is_unchanged(dir, file) {
+            log::warn!(
+                "Warning: {} was changed since it was installed, leaving it in place",
+                file.name
+            );
+            for backup in &file.backups {
+                log::warn!("A file it replaced is kept in {}", backup);
+            }
+            return Ok(false);
+        }
+
+        fs::remove_file(&path).with_context(|| format!("Failed to remove '{}'", path.display()))?;
+        log::info!("Removed {}", path.display());
+    }
+
+    if let Some((backup, older)) = file.backups.split_last() {
+        let backup_path = dir.join(backup);
+        if backup_path.exists() {
+            fs::rename(&backup_path, &path)
+                .with_context(|| format!("Failed to restore '{}'", backup_path.display()))?;
+            log::info!("Restored {} from {}", file.name, backup);
+        }
+
+        for backup in older {
+            log::info!("An older version of {} is kept in {}", file.name, backup);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Copies each (source, file name) into `dir` and records them in the manifest of the modpack called `name`. The files
+/// of the last install that aren't part of this one are removed, and files the tool didn't place are backed up before
+/// they are replaced. Returns the paths of the installed files
+pub(crate) fn install(
+    dir: &Path,
+    name: &str,
+    config: Option<&Path>,
+    files: &[(PathBuf, String)],
+) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+
+    let manifest_path = InstallManifest::path(dir, name);
+    let previous = InstallManifest::read(&manifest_path)?.unwrap_or_default();
+
+    // The manifest is written after every file, so an install that fails partway still records what it placed and
+    // uninstall can undo it. The files of the last install that are left in place stay recorded along with their
+    // backups
+    let mut manifest = InstallManifest {
+        config: config.map(Path::to_path_buf),
+        installed_at: now(),
+        files: Vec::new(),
+    };
+    for file in &previous.files {
+        let reinstalled = files.iter().any(|(_, file_name)| *file_name == file.name);
+        if reinstalled || !remove_installed(dir, file)? {
+            manifest.files.push(file.clone());
+        }
+    }
+    manifest.write(&manifest_path)?;
+    let mut installed = Vec::new();
+
+    for (source, file_name) in files {
+        let path = dir.join(file_name);
+        let previous_file = previous.files.iter().find(|file| file.name == *file_name);
+
+        // Anything other than the file the last install placed there is kept rather than overwritten
+        let mut backups = previous_file
+            .map(|file| file.backups.clone())
+            .unwrap_or_default();
+        let placed_by_us = previous_file.is_some_and(|file| is_unchanged(dir, file));
+        let new_backup = if path.exists() && !placed_by_us {
+            Some(back_up(dir, name, file_name)?)
+        } else {
+            None
+        };
+
+        log::info!("Copying {} to {}", source.display(), path.display());
+        if let Err(e) = fs::copy(source, &path) {
+            // Put back the file that was just backed up, so nothing is left unrecorded
+            if let Some(backup) = &new_backup {
+                let _ = fs::remove_file(&path);
+                fs::rename(dir.join(backup), &path)
+                    .with_context(|| format!("Failed to restore '{}'", backup))?;
+            }
+
+            return Err(e).with_context(|| format!("Failed to copy '{}'", source.display()));
+        }
+
+        backups.extend(new_backup);
+        manifest.record(InstalledFile {
+            name: file_name.clone(),
+            hash: build_state::hash_file(&path)?,
+            installed_at: manifest.installed_at,
+            backups,
+        });
+        manifest.write(&manifest_path)?;
+        installed.push(path);
+    }
+
+    Ok(installed)
+}
+
+/// Removes the files of the modpack called `name` from `dir` and restores the files they replaced. The files that
+/// were changed since they were installed are left in place and stay in the manifest, so their backups aren't lost.
+/// Returns false if nothing was installed
+pub(crate) fn uninstall(dir: &Path, name: &str) -> Result<bool> {
+    let manifest_path = InstallManifest::path(dir, name);
+    let Some(mut manifest) = InstallManifest::read(&manifest_path)? else {
+        return Ok(false);
+    };
+
+    let mut kept = Vec::new();
+    for file in manifest.files {
+        if !remove_installed(dir, &file)? {
+            kept.push(file);
+        }
+    }
+
+    if kept.is_empty() {
+        fs::remove_file(&manifest_path)
+            .with_context(|| format!("Failed to remove '{}'", manifest_path.display()))?;
+    } else {
+        manifest.files = kept;
+        manifest.write(&manifest_path)?;
+    }
+    // Only removed when every backup was restored
+    let _ = fs::remove_dir(dir.join(backup_dir(name)));
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "modpack";
+
+    /// Returns a copy_to_dir and a function that installs the given (file name, contents) into it
+    fn setup(test: &str) -> (PathBuf, impl Fn(&[(&str, &str)])) {
+        let root =
+            std::env::temp_dir().join(format!("upmm_install_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("copy_to_dir");
+        fs::create_dir_all(&dir).unwrap();
+
+        let sources = root.join("sources");
+        let install_files = move |files: &[(&str, &str)]| {
+            fs::create_dir_all(&sources).unwrap();
+            let files: Vec<(PathBuf, String)> = files
+                .iter()
+                .map(|(name, contents)| {
+                    let source = sources.join(name);
+                    fs::write(&source, contents).unwrap();
+                    (source, name.to_string())
+                })
+                .collect();
+            install(&root.join("copy_to_dir"), NAME, None, &files).unwrap();
+        };
+
+        (dir, install_files)
+    }
+
+    fn read(dir: &Path, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    fn manifest(dir: &Path) -> Option<InstallManifest> {
+        InstallManifest::read(&InstallManifest::path(dir, NAME)).unwrap()
+    }
+
+    fn cleanup(dir: &Path) {
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn replaced_files_are_restored_on_uninstall() {
+        let (dir, install_files) = setup("restore");
+        fs::write(dir.join("a.pak"), "original").unwrap();
+
+        install_files(&[("a.pak", "v1")]);
+        assert_eq!(read(&dir, "a.pak"), "v1");
+
+        // Installing again replaces the tool's own file without another backup
+        install_files(&[("a.pak", "v2")]);
+        assert_eq!(read(&dir, "a.pak"), "v2");
+        assert_eq!(manifest(&dir).unwrap().files[0].backups.len(), 1);
+
+        assert!(uninstall(&dir, NAME).unwrap());
+        assert_eq!(read(&dir, "a.pak"), "original");
+        assert!(manifest(&dir).is_none());
+        assert!(!dir.join(backup_dir(NAME)).exists());
+
+        cleanup(&dir);
+    }
+
+    #[test]
+    fn files_put_in_place_of_installed_files_are_backed_up() {
+        let (dir, install_files) = setup("user_file");
+        fs::write(dir.join("a.pak"), "original").unwrap();
+        install_files(&[("a.pak", "v1")]);
+
+        fs::write(dir.join("a.pak"), "mine").unwrap();
+        install_files(&[("a.pak", "v2")]);
+        assert_eq!(read(&dir, "a.pak"), "v2");
+        assert_eq!(manifest(&dir).unwrap().files[0].backups.len(), 2);
+
+        // The newest file it replaced comes back, the original stays in the backup directory
+        assert!(uninstall(&dir, NAME).unwrap());
+        assert_eq!(read(&dir, "a.pak"), "mine");
+        assert_eq!(fs::read_dir(dir.join(backup_dir(NAME))).unwrap().count(), 1);
+
+        cleanup(&dir);
+    }
+
+    #[test]
+    fn changed_files_stay_recorded_on_uninstall() {
+        let (dir, install_files) = setup("changed");
+        fs::write(dir.join("a.pak"), "original").unwrap();
+        install_files(&[("a.pak", "v1"), ("b.pak", "v1")]);
+
+        fs::write(dir.join("a.pak"), "mine").unwrap();
+        assert!(uninstall(&dir, NAME).unwrap());
+        assert_eq!(read(&dir, "a.pak"), "mine");
+        assert!(!dir.join("b.pak").exists());
+
+        let manifest = manifest(&dir).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert!(dir.join(&manifest.files[0].backups[0]).exists());
+
+        cleanup(&dir);
+    }
+
+    #[test]
+    fn files_left_out_of_the_next_install_are_removed() {
+        let (dir, install_files) = setup("removed");
+        install_files(&[("a.pak", "v1"), ("b.pak", "v1")]);
+
+        install_files(&[("a.pak", "v2")]);
+        assert!(!dir.join("b.pak").exists());
+        assert_eq!(manifest(&dir).unwrap().files.len(), 1);
+
+        cleanup(&dir);
+    }
+}
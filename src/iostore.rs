@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use path_slash::PathExt as _;
+
+// IoStore containers are a .utoc (table of contents) and .ucas (data) pair, usually shipped next to a small .pak
+// The asset data is almost always Oodle compressed so it can't be extracted and merged like .pak contents. Instead
+// the directory index of the .utoc is read so overlapping assets between mods can be reported
+
+const TOC_MAGIC: &[u8; 16] = b"-==--==--==--==-";
+const TOC_HEADER_SIZE: u64 = 144;
+
+// EIoStoreTocVersion
+const VERSION_DIRECTORY_INDEX: u8 = 2;
+const VERSION_PERFECT_HASH: u8 = 4;
+const VERSION_PERFECT_HASH_WITH_OVERFLOW: u8 = 5;
+
+// EIoContainerFlags
+const FLAG_ENCRYPTED: u8 = 1 << 1;
+const FLAG_SIGNED: u8 = 1 << 2;
+const FLAG_INDEXED: u8 = 1 << 3;
+
+const INVALID_INDEX: u32 = u32::MAX;
+
+struct TocReader<R: Read> {
+    reader: R,
+    /// The number of bytes left to read, so the lengths in the file are checked before anything is allocated for them
+    remaining: u64,
+}
+
+impl<R: Read> TocReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf)?;
+        self.remaining = self.remaining.saturating_sub(N as u64);
+        Ok(buf)
+    }
+
+    fn byte_vec(&mut self, len: u64) -> Result<Vec<u8>> {
+        if len > self.remaining {
+            return Err(anyhow!(
+                "utoc directory index is truncated, {} bytes are needed but only {} are left",
+                len,
+                self.remaining
+            ));
+        }
+
+        let mut buf = vec![0; len as usize];
+        self.reader.read_exact(&mut buf)?;
+        self.remaining -= len;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    /// Reads an FString, which is either UTF-8 (positive length) or UTF-16 (negative length) and null terminated
+    fn string(&mut self) -> Result<String> {
+        let len = self.i32()?;
+
+        let string = if len >= 0 {
+            let buf = self.byte_vec(len as u64)?;
+            String::from_utf8_lossy(&buf).to_string()
+        } else {
+            let buf = self.byte_vec(len.unsigned_abs() as u64 * 2)?;
+            let chars: Vec<u16> = buf
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&chars)
+        };
+
+        Ok(string.trim_end_matches('\0').to_string())
+    }
+
+    fn array<T>(&mut self, mut read: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let count = self.u32()?;
+        // Every element takes at least a byte
+        if count as u64 > self.remaining {
+            return Err(anyhow!(
+                "utoc directory index is truncated, it has {} elements but only {} bytes are left",
+                count,
+                self.remaining
+            ));
+        }
+
+        (0..count).map(|_| read(self)).collect()
+    }
+}
+
+/// The IoStore container shipped by a mod
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Container {
+    pub branch: String,
+    pub utoc_path: PathBuf,
+    /// The assets in the container, relative to the game root
+    pub files: Vec<String>,
+}
+
+/// An asset that is shipped in the IoStore containers of more than one mod
+#[derive(Debug, Clone)]
+pub struct AssetConflict {
+    pub path: String,
+    /// The branches of the mods that contain the asset, in merge order
+    pub branches: Vec<String>,
+}
+
+/// Finds the assets that are in more than one container. Containers are expected to be in merge order
+pub fn find_conflicts(containers: &[Container]) -> Vec<AssetConflict> {
+    let mut assets: BTreeMap<String, AssetConflict> = BTreeMap::new();
+
+    for container in containers {
+        for file in &container.files {
+            assets
+                .entry(file.to_lowercase())
+                .or_insert_with(|| AssetConflict {
+                    path: file.clone(),
+                    branches: Vec::new(),
+                })
+                .branches
+                .push(container.branch.clone());
+        }
+    }
+
+    assets
+        .into_values()
+        .filter(|asset| asset.branches.len() > 1)
+        .collect()
+}
+
+struct DirectoryEntry {
+    name: u32,
+    first_child: u32,
+    next_sibling: u32,
+    first_file: u32,
+}
+
+struct FileEntry {
+    name: u32,
+    next_file: u32,
+}
+
+/// Returns the path of every file in the IoStore container, relative to the game root (i.e. the same way .pak files
+/// are extracted into the staging directory). `game_root` is the mount point of the game root, usually "../../../"
+pub fn list_files(utoc_path: &Path, game_root: &str) -> Result<Vec<String>> {
+    let file = std::fs::File::open(utoc_path)
+        .with_context(|| format!("Failed to open utoc file '{}'", utoc_path.display()))?;
+    let file_size = file.metadata()?.len();
+    let mut toc = TocReader {
+        reader: std::io::BufReader::new(file),
+        remaining: file_size,
+    };
+
+    if &toc.bytes::<16>()? != TOC_MAGIC {
+        return Err(anyhow!("'{}' is not a utoc file", utoc_path.display()));
+    }
+
+    let version = toc.u8()?;
+    toc.bytes::<3>()?;
+    let _header_size = toc.u32()?;
+    let entry_count = toc.u32()? as u64;
+    let compressed_block_count = toc.u32()? as u64;
+    let _compressed_block_size = toc.u32()?;
+    let compression_method_count = toc.u32()? as u64;
+    let compression_method_length = toc.u32()? as u64;
+    let _compression_block_size = toc.u32()?;
+    let directory_index_size = toc.u32()? as u64;
+    let _partition_count = toc.u32()?;
+    let _container_id = toc.bytes::<8>()?;
+    let _encryption_key_guid = toc.bytes::<16>()?;
+    let flags = toc.u8()?;
+    toc.bytes::<3>()?;
+    let perfect_hash_seed_count = toc.u32()? as u64;
+    let _partition_size = toc.bytes::<8>()?;
+    let chunks_without_perfect_hash_count = toc.u32()? as u64;
+
+    if version < VERSION_DIRECTORY_INDEX || flags & FLAG_INDEXED == 0 || directory_index_size == 0 {
+        return Err(anyhow!(
+            "'{}' does not contain a directory index",
+            utoc_path.display()
+        ));
+    }
+
+    if flags & FLAG_ENCRYPTED != 0 {
+        return Err(anyhow!(
+            "'{}' has an encrypted directory index",
+            utoc_path.display()
+        ));
+    }
+
+    // Skip everything between the header and the directory index
+    let mut offset = TOC_HEADER_SIZE
+        // Chunk ids
+        + entry_count * 12
+        // Chunk offsets and lengths
+        + entry_count * 10
+        // Compression blocks
+        + compressed_block_count * 12
+        // Compression method names
+        + compression_method_count * compression_method_length;
+
+    if version >= VERSION_PERFECT_HASH {
+        offset += perfect_hash_seed_count * 4;
+    }
+
+    if version >= VERSION_PERFECT_HASH_WITH_OVERFLOW {
+        offset += chunks_without_perfect_hash_count * 4;
+    }
+
+    let mut reader = toc.reader;
+
+    if flags & FLAG_SIGNED != 0 {
+        reader.seek(SeekFrom::Start(offset))?;
+        let hash_size = TocReader {
+            reader: &mut reader,
+            remaining: file_size,
+        }
+        .u32()? as u64;
+
+        // Hash size, toc signature, block signature and a SHA1 hash for each compression block
+        offset += 4 + hash_size * 2 + compressed_block_count * 20;
+    }
+
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut index = TocReader {
+        reader: reader.take(directory_index_size),
+        remaining: directory_index_size,
+    };
+
+    let mount_point = index.string()?;
+    let directories = index.array(|r| {
+        Ok(DirectoryEntry {
+            name: r.u32()?,
+            first_child: r.u32()?,
+            next_sibling: r.u32()?,
+            first_file: r.u32()?,
+        })
+    })?;
+    let files = index.array(|r| {
+        let name = r.u32()?;
+        let next_file = r.u32()?;
+        let _user_data = r.u32()?;
+        Ok(FileEntry { name, next_file })
+    })?;
+    let strings = index.array(|r| r.string())?;
+
+    if directories.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let name = |idx: u32| -> Result<&str> {
+        strings
+            .get(idx as usize)
+            .map(String::as_str)
+            .context("utoc directory index refers to a missing name")
+    };
+
+    // Every directory and file is only reached once, so a chain that loops back on itself is an error rather than an
+    // endless walk
+    let visit = |visited: &mut [bool], idx: u32, kind: &str| -> Result<()> {
+        let visited = visited
+            .get_mut(idx as usize)
+            .with_context(|| format!("utoc directory index refers to a missing {}", kind))?;
+        if *visited {
+            return Err(anyhow!(
+                "utoc directory index reaches {} {} more than once",
+                kind,
+                idx
+            ));
+        }
+
+        *visited = true;
+        Ok(())
+    };
+    let mut visited_directories = vec![false; directories.len()];
+    let mut visited_files = vec![false; files.len()];
+
+    let mut paths = Vec::new();
+    // (directory index, path of the directory)
+    let mut stack = vec![(0u32, String::new())];
+    visit(&mut visited_directories, 0, "directory")?;
+
+    while let Some((dir_idx, dir_path)) = stack.pop() {
+        let dir = &directories[dir_idx as usize];
+
+        let mut file_idx = dir.first_file;
+        while file_idx != INVALID_INDEX {
+            visit(&mut visited_files, file_idx, "file")?;
+            let file = &files[file_idx as usize];
+            paths.push(format!("{}{}", dir_path, name(file.name)?));
+            file_idx = file.next_file;
+        }
+
+        let mut child_idx = dir.first_child;
+        while child_idx != INVALID_INDEX {
+            visit(&mut visited_directories, child_idx, "directory")?;
+            let child = &directories[child_idx as usize];
+            stack.push((child_idx, format!("{}{}/", dir_path, name(child.name)?)));
+            child_idx = child.next_sibling;
+        }
+    }
+
+    let mount_point = mount_point.trim_end_matches('/');
+    let mut paths: Vec<String> = paths
+        .into_iter()
+        .filter_map(|path| {
+            crate::pak::resolve_pak_path(&format!("{}/{}", mount_point, path), game_root)
+                .and_then(|path| path.to_slash().map(|path| path.into_owned()))
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, value: &str) {
+        out.extend((value.len() as i32 + 1).to_le_bytes());
+        out.extend(value.as_bytes());
+        out.push(0);
+    }
+
+    fn u32s(out: &mut Vec<u8>, values: &[u32]) {
+        for value in values {
+            out.extend(value.to_le_bytes());
+        }
+    }
+
+    /// Writes a utoc with no chunks and the given directory index, returning its path
+    fn write_utoc(name: &str, index: &[u8]) -> PathBuf {
+        let mut utoc = TOC_MAGIC.to_vec();
+        utoc.extend([VERSION_DIRECTORY_INDEX, 0, 0, 0]);
+        // Header size, entry count, compressed block count and size, compression method count and length,
+        // compression block size, directory index size and partition count
+        u32s(
+            &mut utoc,
+            &[
+                TOC_HEADER_SIZE as u32,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                index.len() as u32,
+                1,
+            ],
+        );
+        // Container id and encryption key guid
+        utoc.extend([0; 24]);
+        utoc.extend([FLAG_INDEXED, 0, 0, 0]);
+        utoc.resize(TOC_HEADER_SIZE as usize, 0);
+        utoc.extend(index);
+
+        let path =
+            std::env::temp_dir().join(format!("upmm-test-{}-{}.utoc", std::process::id(), name));
+        std::fs::write(&path, utoc).unwrap();
+        path
+    }
+
+    /// A directory index with a Maps directory holding Zone.umap and Lab.umap. `next_file` is what Lab.umap links to
+    fn index(next_file: u32) -> Vec<u8> {
+        let mut index = Vec::new();
+        string(&mut index, "../../../Stalker2/Content/");
+        // Directories: the root, then Maps
+        u32s(&mut index, &[2]);
+        u32s(
+            &mut index,
+            &[INVALID_INDEX, 1, INVALID_INDEX, INVALID_INDEX],
+        );
+        u32s(&mut index, &[0, INVALID_INDEX, INVALID_INDEX, 0]);
+        // Files: Zone.umap, then Lab.umap
+        u32s(&mut index, &[2]);
+        u32s(&mut index, &[1, 1, 0]);
+        u32s(&mut index, &[2, next_file, 0]);
+        u32s(&mut index, &[3]);
+        string(&mut index, "Maps");
+        string(&mut index, "Zone.umap");
+        string(&mut index, "Lab.umap");
+        index
+    }
+
+    #[test]
+    fn lists_the_files_of_the_directory_index() {
+        let path = write_utoc("list", &index(INVALID_INDEX));
+        let files = list_files(&path, "../../../");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            files.unwrap(),
+            [
+                "Stalker2/Content/Maps/Lab.umap",
+                "Stalker2/Content/Maps/Zone.umap"
+            ]
+        );
+    }
+
+    #[test]
+    fn looping_file_chains_are_an_error() {
+        let path = write_utoc("loop", &index(0));
+        let result = list_files(&path, "../../../");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("more than once"));
+    }
+
+    #[test]
+    fn lengths_past_the_end_of_the_index_are_an_error() {
+        let mut index = Vec::new();
+        u32s(&mut index, &[i32::MAX as u32]);
+        let path = write_utoc("length", &index);
+        let result = list_files(&path, "../../../");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("truncated"));
+    }
+}
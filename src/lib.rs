@@ -0,0 +1,27 @@
+//! Merges a collection of Unreal Engine .pak mods into a single modpack
+//!
+//! The CLI is a thin wrapper around [`ModpackBuilder`], which can also be used to drive builds from other tools
+//!
+//! Progress messages and warnings are emitted with the [`log`] crate, so they're only shown when a logger is set
+
+mod archive;
+mod build_state;
+pub mod config;
+mod content_root;
+pub mod error;
+pub mod formats;
+pub mod git;
+pub mod install;
+pub mod iostore;
+pub mod merge;
+pub mod modpack;
+mod pak;
+pub mod provenance;
+pub mod stalker2_cfg;
+pub mod unreal_ini;
+
+pub use config::{
+    Compression, DeployMode, OutputMode, PakVersion, UpmmConfig, UpmmModConfig, UpmmProfile,
+};
+pub use error::ErrorKind;
+pub use modpack::{BuildReport, DisabledReason, MergeReport, MergedFile, ModInfo, ModpackBuilder};
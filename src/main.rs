@@ -1,90 +1,15 @@
-extern crate git2;
-
-use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::BufWriter,
-    path::PathBuf,
-};
+use std::{fs, io::IsTerminal, path::PathBuf, process::ExitCode};
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use git2::Repository;
-use path_slash::PathExt as _;
-
-pub mod git;
-pub mod merge;
-pub mod stalker2_cfg;
-pub mod unreal_ini;
-
-#[derive(serde::Deserialize, Clone)]
-struct UpmmModConfig {
-    // The priority of the mod
-    // Lower numbers are merged first, meaning changes in mod priority=2 will take priority over changes in mod priority=1
-    // Without an explicit priority set, the mods priority is set via alphabetical order
-    // For example, "a.pak", "b.pak", and "c.pak" will have priorities 0, 1, and 2 respectively
-    // As such, it's recommended to set priorities above 1000 and below -1000 to ensure adding new mods won't affect existing priorities
-    priority: Option<i64>,
-}
-
-#[derive(serde::Deserialize)]
-struct UpmmConfig {
-    // The name of the modpack
-    name: String,
-
-    // The directory where all of the files are staged before being added to the .pak file
-    // This directory will be a git repository so you can use git to look at the history of the files
-    // Each input mod will contain it's own branch and merge commit
-    staging_dir: String,
-
-    // The directory where the mods are located
-    // The directory can contain either:
-    // - Directories that are essentially unpacked .pak files (assumes default mount point of "../../../")
-    // - .pak files
-    mods_dir: String,
-
-    // mods.<mod_name> allows you to set mod-specific options
-    mods: Option<HashMap<String, UpmmModConfig>>,
-
-    // The path to copy the .pok to
-    // TODO: Find the installation path. For Steam it's in
-    // HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows\CurrentVersion\Uninstall\Steam App 1643320 | REG_SZ InstallLocation
-    copy_to_dir: Option<String>
-}
-
-const DEFAULT_CONFIG_FILE: &str = r#"
-# The name of .pak file that is created
-name = "upmm_modpack"
-
-# All directories in this config are relative to the location of this config file
-
-# The directory where all of the files are staged before being added to the .pak file
-# This directory will be a git repository so you can use git to look at the history of the files
-# Each input mod will contain it's own branch and merge commit
-staging_dir = "staging"
-
-# The directory where the mods are located
-# The directory can contain either:
-# - Directories that are essentially unpacked .pak files (assumes default mount point of "../../../")
-# - .pak files
-mods_dir = "mods"
+use unreal_pak_mod_manager::{config, iostore, provenance, ErrorKind, MergeReport, ModpackBuilder};
 
-# mods.<mod_name> allows you to set mod-specific options
-
-# An optional directory to copy the finished pak file to
-# Setting it to your ~mods directory will mean you don't need to manually copy the .pak over each time you build
-# Below is the default Steam install location. Uncomment the line and edit to your install path
-# copy_to_dir = "C:\\Program Files (x86)\\Steam\\steamapps\\common\\S.T.A.L.K.E.R. 2 Heart of Chornobyl\\Stalker2\\Content\\Paks\\~mods"
-
-# mods.<mod_name>.priority sets the order in which the mods are merged into the final mod pack
-# Lower numbers are merged first, meaning changes in mod priority=2 will take priority over changes in mod priority=1
-# Without an explicit priority set, the mods priority is set via alphabetical order
-# For example, "a.pak", "b.pak", and "c.pak" will have priorities 0, 1, and 2 respectively
-# As such, it's recommended to set priorities above 1000 and below -1000 to ensure adding new mods won't affect existing priorities
-
-# [mods."zzzz_Grok_Boar-40pHP_P.pak"]
-# priority = -1000 # Merge this mod first
-"#;
+// Exit codes, so scripts wrapping the CLI can tell failures apart. clap exits with 2 on invalid arguments
+const EXIT_ERROR: u8 = 1;
+const EXIT_CONFIG: u8 = 3;
+const EXIT_EXTRACTION: u8 = 4;
+const EXIT_UNRESOLVED_CONFLICTS: u8 = 5;
+const EXIT_PAK_WRITE: u8 = 6;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -92,433 +17,374 @@ struct Args {
     /// Path to the configuration file
     #[arg(
         value_name = "CONFIG_FILE",
-        help = "Path to the configuration file. If not given, assume config.toml in current directory. If config.toml is not found, create it."
+        help = "Path to the configuration file. If not given, assume config.toml in current directory. If config.toml is not found when building, create it."
     )]
     config_file: Option<String>,
-}
 
-fn unpak_pak(path: &std::path::Path, output_dir: &std::path::Path) -> Result<()> {
-    let pak = repak::PakBuilder::new().reader(&mut std::io::BufReader::new(
-        File::open(path)
-            .with_context(|| format!("Failed to open pak file '{}'", path.display()))?,
-    ))?;
+    /// Exit without waiting for Enter to be pressed. This is the default when stdin is not a terminal
+    #[arg(long, global = true)]
+    no_pause: bool,
 
-    // Extract each file
-    for entry_path in pak.files() {
-        let relative_out_path =
-            PathBuf::from(pak.mount_point().replace("../../../", "")).join(&entry_path);
+    /// Build with the overrides of profiles.<PROFILE> in the config
+    #[arg(long, global = true)]
+    profile: Option<String>,
 
-        let out_path = output_dir.join(&relative_out_path);
+    /// Delete the staging directory even when it wasn't created by a previous build or contains the config or mods
+    /// directory
+    #[arg(long, global = true)]
+    force_staging: bool,
 
-        println!(
-            "{}: Extracting {}",
-            path.file_name()
-                .expect("should be able to get filename from path")
-                .to_str()
-                .expect("should be able to get str from filename"),
-            relative_out_path
-                .to_str()
-                .expect("should be able to get str from path"),
-        );
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-        // Create parent directories
-        if let Some(parent) = out_path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create directory '{}'", parent.display()))?;
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Write the default config file
+    Init {
+        /// Overwrite the config file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// List the mods in the order they are merged, along with their priorities and whether they are skipped
+    List,
+
+    /// Merge the mods and create the modpack (the default when no command is given)
+    Build,
+
+    /// Merge the mods without creating the modpack and list the files and values that more than one mod changed. The
+    /// staging directory of the last build is left alone
+    Conflicts,
+
+    /// Show the changes a mod made in the last build, compared to the vanilla files
+    Diff {
+        /// The mod's file name in the mods directory, i.e. zzz_Grok_Boar-40percent_HP_P.pak
+        name: String,
+    },
+
+    /// Delete the staging directory so the next build starts from scratch
+    Clean,
+
+    /// Remove the files the last build installed in copy_to_dir and restore the files they replaced
+    Uninstall,
+
+    /// Show which mod set the values in a file of the last built modpack
+    Blame {
+        /// Path of the file within the modpack, i.e. Stalker2/Content/GameLite/GameData/ObjPrototypes/Bloodsucker.cfg
+        file: String,
+
+        /// Only show this value (and the values nested under it), i.e. Bloodsucker/VitalParams/MaxHP
+        key: Option<String>,
+    },
+}
+
+fn print_conflict_summary(report: &MergeReport) {
+    let conflicts = &report.conflicts;
+    let containers = &report.containers;
+
+    if !containers.is_empty() {
+        println!("IoStore containers can't be merged into the modpack, keep these installed alongside it:");
+        for container in containers {
+            println!("{}: {}", container.branch, container.utoc_path.display());
         }
 
-        // Extract the file to a string first
-        let mut content = Vec::new();
-        pak.read_file(
-            &entry_path,
-            &mut std::io::BufReader::new(File::open(path)?),
-            &mut content,
-        )?;
-
-        // Normalize and write the content
-        let normalized = normalize_content(&out_path, &content)?;
-        fs::write(&out_path, normalized).context(format!(
-            "failed to write to {}",
-            &out_path.to_str().context("Failed to get str from path")?
-        ))?;
+        for asset in iostore::find_conflicts(containers) {
+            println!(
+                "{}: IoStore asset is in multiple mods, only the one that loads last will be used: {}",
+                asset.path,
+                asset.branches.join(", ")
+            );
+        }
     }
 
-    Ok(())
-}
+    if conflicts.is_empty() {
+        println!("No conflicting values between mods");
+        return;
+    }
 
-fn normalize_content(path: &std::path::Path, content: &Vec<u8>) -> Result<Vec<u8>> {
-    match path.extension().and_then(|ext| ext.to_str()) {
-        Some("json") => {
-            let str_content = String::from_utf8(content.to_vec())
-                .context(format!("non-utf8 bytes found in {}", path.display()))?;
-            let json: serde_json::Value = serde_json::from_str(&str_content)?;
-            Ok(serde_json::to_string_pretty(&json)?.into_bytes())
+    fn display(value: &Option<String>) -> &str {
+        value.as_deref().unwrap_or("<removed>")
+    }
+
+    println!("Conflicting values between mods (the later mod's value was kept):");
+    for file in conflicts {
+        if file.overwritten {
+            println!(
+                "{}: could not merge values, merged lines instead and kept the lines of {} where both changed them",
+                file.path, file.branch
+            );
+            continue;
         }
-        Some("cfg") => {
-            let str_content = String::from_utf8(content.to_vec())
-                .context(format!("non-utf8 bytes found in {}", path.display()))?;
-
-            let cfg = stalker2_cfg::Stalker2Cfg::from_str(
-                path.file_name()
-                    .expect("should always be able to get the filename from the path")
-                    .to_str()
-                    .expect("should always be able to get the str from the filename")
-                    .to_string(),
-                &str_content,
-            )?;
-
-            Ok(cfg.to_string().into_bytes())
+
+        for conflict in &file.conflicts {
+            println!(
+                "{}: {}: {} kept {} (base {}, previously {})",
+                file.path,
+                conflict.path,
+                file.branch,
+                display(&conflict.theirs),
+                display(&conflict.base),
+                display(&conflict.ours),
+            );
         }
-        _ => Ok(content.to_vec()),
     }
 }
 
-fn process_all_mods_dirs(
-    mods_dir: &std::path::Path,
-    repo: &Repository,
-    config: &UpmmConfig,
-) -> Result<()> {
-    fn process_dir(
-        dir: &std::path::Path,
-        root_dir: &std::path::Path,
-        repo: &Repository,
-    ) -> Result<()> {
-        for entry in std::fs::read_dir(dir)
-            .with_context(|| format!("Failed to read directory '{}'", dir.display()))?
-        {
-            let path = entry
-                .with_context(|| "Failed to read directory entry")?
-                .path();
-
-            if path.is_dir() {
-                process_dir(&path, root_dir, repo)?;
-            } else {
-                // Path relative to raw dir
-                let relative_path = path.strip_prefix(root_dir)?;
-                let repo_parent = repo
-                    .path()
-                    .parent()
-                    .expect("should always be able to get the parent of the repo path");
-
-                // Create parent directories
-                if let Some(parent) = repo_parent.join(relative_path).parent() {
-                    fs::create_dir_all(parent).with_context(|| {
-                        format!("Failed to create directory '{}'", parent.display())
-                    })?;
-                }
-
-                println!(
-                    "{}: Copying {}",
-                    root_dir
-                        .file_name()
-                        .expect("should always be able to get the filename from the root dir")
-                        .to_str()
-                        .expect("should always be able to get the filename from the root dir"),
-                    relative_path.display(),
-                );
-
-                let content = normalize_content(
-                    &path,
-                    &std::fs::read(&path)
-                        .context(format!("Failed to read file '{}'", path.display()))?,
-                )?;
-
-                std::fs::write(repo_parent.join(relative_path), content).context(format!(
-                    "Failed to write file '{}'",
-                    relative_path.display()
-                ))?;
-            }
-        }
+/// Creates the mods directory if needed. Returns false if there are no mods to build yet
+fn check_mods_dir(builder: &ModpackBuilder) -> Result<bool> {
+    let full_mods_dir = builder.mods_dir();
+
+    if !full_mods_dir.exists() {
+        fs::create_dir_all(&full_mods_dir).with_context(|| {
+            format!(
+                "Failed to create mods directory '{}'",
+                full_mods_dir.display()
+            )
+        })?;
+        let absolute_mods_dir: PathBuf = fs::canonicalize(&full_mods_dir).with_context(|| {
+            format!(
+                "Failed to get absolute path for '{}'",
+                full_mods_dir.display()
+            )
+        })?;
 
-        Ok(())
+        println!(
+            "Created mods directory, put pak files here and run this program again to create a modpack: {}",
+            absolute_mods_dir.display()
+        );
+        return Ok(false);
     }
 
-    let mut entries: Vec<_> = std::fs::read_dir(mods_dir)
-        .with_context(|| format!("Failed to read mods directory '{}'", mods_dir.display()))?
-        .filter_map(Result::ok)
-        .collect();
-
-    // Sort entries by name, this will ensure decrease_health comes before decrease_health_again
-    entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
-
-    let mut priority_map: HashMap<PathBuf, i64> = HashMap::new();
-    let mut current_idx = 0;
-
-    for entry in &entries {
-        let path = entry.path();
-        let mod_name = path
-            .file_name()
-            .expect("should always be able to get the filename from the path")
-            .to_str()
-            .expect("should always be able to get the str from the filename");
-
-        let priority = config
-            .mods
-            .as_ref()
-            .and_then(|v| v.get(mod_name))
-            .and_then(|v| v.priority)
-            .unwrap_or(current_idx);
-
-        priority_map.insert(path, priority);
-        current_idx += 1;
+    if fs::read_dir(&full_mods_dir)?.count() == 0 {
+        let absolute_mods_dir: PathBuf = fs::canonicalize(&full_mods_dir)?;
+
+        println!(
+            "Mods directory is empty, put pak files here and run this program again to create a modpack: {}",
+            absolute_mods_dir.display()
+        );
+        return Ok(false);
     }
 
-    // Sort entries based on their priorities
-    entries.sort_by_key(|entry| {
-        priority_map
-            .get(&entry.path())
-            .expect("should always be able to get the priority from the priority map")
-    });
-
-    println!("Processing the mods in the following order:");
-    for entry in &entries {
-        let path = entry.path();
-        let priority = priority_map
-            .get(&path)
-            .expect("should always be able to get the priority from the priority map");
-
-        println!("{}: {}", priority, path.display());
+    Ok(true)
+}
+
+/// Returns true if some files couldn't be merged value by value
+fn create_modpack(builder: &ModpackBuilder) -> Result<bool> {
+    if !check_mods_dir(builder)? {
+        return Ok(false);
     }
 
-    for entry in &entries {
-        let path = entry.path();
-        let branch_name: String = path
-            .file_name()
-            .expect("should always be able to get the filename from the path")
-            .to_str()
-            .expect("should always be able to get the str from the filename")
-            .to_string();
-
-        // First add untracked files to master
-        git::checkout_branch(repo, "master").expect("Failed to checkout master");
-
-        if path.is_dir() {
-            process_dir(&path, &path, repo)?;
-        } else if path.extension().map_or(false, |ext| ext == "pak") {
-            unpak_pak(
-                &path,
-                &repo
-                    .path()
-                    .parent()
-                    .expect("should always be able to get the parent of the repo path"),
-            )?;
-        } else {
-            panic!("Unknown file type: {}", path.display());
+    let report = builder.build()?;
+    print_conflict_summary(&report.merge);
+
+    if !report.required_mods.is_empty() {
+        println!("The modpack only contains the files that more than one mod changed, keep these mods installed alongside it:");
+        for mod_path in &report.required_mods {
+            println!("{}", mod_path.display());
         }
+    }
 
-        git::commit_files(repo, &branch_name, true).expect("Failed to commit untracked_files");
+    Ok(report.merge.has_unresolved_conflicts())
+}
 
-        // Now checkout branch for this root dir and add tracked files
-        git::checkout_branch(repo, &branch_name).expect("Failed to checkout branch");
-        git::commit_files(repo, &branch_name, false).expect("Failed to commit tracked files");
+fn init(config_path: &std::path::Path, force: bool) -> Result<()> {
+    if config_path.exists() && !force {
+        return Err(anyhow::anyhow!(
+            "'{}' already exists, use init --force to overwrite it",
+            config_path.display()
+        ));
     }
 
-    for entry in &entries {
-        let path = entry.path();
-        let priority = priority_map
-            .get(&path)
-            .expect("should always be able to get the priority from the priority map");
-
-        let branch = git::normalize_git_ref(
-            path.file_name()
-                .expect("should always be able to get the filename from the path")
-                .to_str()
-                .expect("should always be able to get the str from the filename"),
-        );
+    fs::write(config_path, config::DEFAULT_CONFIG_FILE)
+        .with_context(|| format!("Failed to write config file '{}'", config_path.display()))?;
+    println!("Created config file: {}", config_path.display());
 
-        println!("{}: Merging with priority {}", branch, priority);
+    Ok(())
+}
 
-        git::checkout_branch(repo, "master").expect("Failed to checkout master");
-        git::merge_branch(repo, &branch, git::MergeStrategy::Custom)
-            .expect("Failed to merge branch");
+fn list(builder: &ModpackBuilder) -> Result<()> {
+    let mods = builder.list_mods()?;
+    if mods.is_empty() {
+        println!("No mods found in {}", builder.mods_dir().display());
+    }
+
+    for m in mods {
+        match m.disabled {
+            Some(reason) => println!("{}: {} (skipped, {})", m.priority, m.name, reason),
+            None => println!("{}: {}", m.priority, m.name),
+        }
     }
 
     Ok(())
 }
 
-fn create_modpack(config_path: &std::path::Path) -> Result<()> {
-    let config_contents = std::fs::read_to_string(config_path)
-        .with_context(|| format!("Failed to read config file '{}'", config_path.display()))?;
-    let config: UpmmConfig = toml::from_str(&config_contents)?;
-
-    // Get the config file's directory
-    let config_dir = config_path
-        .parent()
-        .unwrap_or_else(|| std::path::Path::new("."));
+/// Returns true if some files couldn't be merged value by value
+fn conflicts(builder: &ModpackBuilder) -> Result<bool> {
+    if !check_mods_dir(builder)? {
+        return Ok(false);
+    }
 
-    // Now use config.staging_dir, config.name, etc. directly
-    let full_staging_dir = config_dir.join(&config.staging_dir);
+    let report = builder.merge()?;
 
-    // Delete the modpack directory if it exists
-    if full_staging_dir.exists() {
-        std::fs::remove_dir_all(full_staging_dir.clone())
-            .with_context(|| format!("Failed to delete modpack directory"))?;
+    if report.merged_files.is_empty() {
+        println!("No files were changed by more than one mod");
+    } else {
+        println!("Files changed by more than one mod:");
+        for file in &report.merged_files {
+            println!("{}: {}", file.path, file.branches.join(", "));
+        }
     }
 
-    std::fs::create_dir_all(full_staging_dir.clone()).with_context(|| {
-        format!(
-            "Failed to create modpack directory '{}'",
-            full_staging_dir.display()
-        )
-    })?;
+    print_conflict_summary(&report);
 
-    let repo: Repository = git::init_repository(
-        full_staging_dir
-            .to_str()
-            .context("Failed to get staging dir str")?,
-    )
-    .expect("Failed to initialize modpack repository");
+    Ok(report.has_unresolved_conflicts())
+}
 
-    let mods_dir = config.mods_dir.clone();
-    let full_mods_dir = config_dir.join(mods_dir);
+fn diff(builder: &ModpackBuilder, name: &str) -> Result<()> {
+    let patch = builder.diff_mod(name)?;
+    if patch.is_empty() {
+        println!("{} did not change any files", name);
+    } else {
+        print!("{}", patch);
+    }
 
-    if !full_mods_dir.exists() {
-        std::fs::create_dir_all(&full_mods_dir).with_context(|| {
-            format!(
-                "Failed to create mods directory '{}'",
-                full_mods_dir.display()
-            )
-        })?;
-        let absolute_mods_dir: PathBuf = fs::canonicalize(&full_mods_dir).with_context(|| {
-            format!(
-                "Failed to get absolute path for '{}'",
-                full_mods_dir.display()
-            )
-        })?;
+    Ok(())
+}
 
+fn clean(builder: &ModpackBuilder) -> Result<()> {
+    if builder.clean()? {
+        println!("Deleted {}", builder.staging_dir().display());
+    } else {
         println!(
-            "Created mods directory, put pak files here and run this program again to create a modpack: {}",
-            absolute_mods_dir.display()
+            "Nothing to clean, {} does not exist",
+            builder.staging_dir().display()
         );
-
-        println!("Press Enter to exit...");
-        std::io::stdin().read_line(&mut String::new())?;
-        return Ok(());
     }
 
-    if std::fs::read_dir(&full_mods_dir)?.count() == 0 {
-        let absolute_mods_dir: PathBuf = fs::canonicalize(&full_mods_dir)?;
+    Ok(())
+}
 
-        println!("Mods directory is empty, put pak files here and run this program again to create a modpack: {}", absolute_mods_dir.display());
-        println!("Press Enter to exit...");
-        std::io::stdin().read_line(&mut String::new())?;
-        return Ok(());
+fn uninstall(builder: &ModpackBuilder) -> Result<()> {
+    if !builder.uninstall()? {
+        println!("Nothing to uninstall, no install manifest was found in copy_to_dir");
     }
 
-    process_all_mods_dirs(&full_mods_dir, &repo, &config)
-        .with_context(|| "Failed to process all input directories")?;
-
-    let name = config.name;
-    let pak_path = config_dir.join(format!("{}.pak", name));
-    let pak_name = pak_path
-        .file_name()
-        .context("Failed to get pak file name")?
-        .to_str()
-        .context("Failed to get file str")?
-        .to_owned();
-    let mut pak = repak::PakBuilder::new().writer(
-        BufWriter::new(File::create(&pak_path).expect("Failed to create pak file")),
-        repak::Version::V8B,
-        "../../../".to_string(),
-        None,
-    );
-
-    fn collect_pak_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
-        for entry in fs::read_dir(dir).context(format!("Failed to read dir {}", dir.display()))? {
-            let entry = entry;
-            let path = entry?.path();
-
-            if path.file_name().context("Failed to get filename")? == ".git" {
-                continue;
-            }
-
-            if path.is_dir() {
-                collect_pak_files(&path, files)?;
-            } else {
-                files.push(path);
-            }
-        }
+    Ok(())
+}
 
-        Ok(())
-    }
+fn blame(builder: &ModpackBuilder, file: &str, key: Option<&str>) -> Result<()> {
+    let provenance = provenance::Provenance::read(&builder.provenance_path())
+        .context("Failed to read provenance of the last build, build the modpack first")?;
 
-    let mut pak_files = Vec::new();
-    collect_pak_files(&full_staging_dir, &mut pak_files)?;
-
-    for path in pak_files {
-        let file_path = path.strip_prefix(&full_staging_dir)?;
-        let path_slash = file_path.to_slash().context("Failed to get slash")?;
-        println!("{}: Packing {}", pak_name, path_slash);
-        pak.write_file(
-            &path_slash,
-            fs::read(&path).expect(&format!("Failed to read {}", path.display())),
-        )
-        .expect(&format!(
-            "Failed to write {} to {}",
-            path_slash,
-            path.display()
-        ));
+    let values = provenance.blame(file, key);
+    if values.is_empty() {
+        println!("No values found for {} in the last build", file);
     }
 
-    pak.write_index()?;
-
-    println!("{} created successfully!", pak_path.display());
-
-    if config.copy_to_dir.is_some() {
-        let copy_to_dir = PathBuf::from(config.copy_to_dir.expect("should exist because we're in .is_some() branch"));
-        
-        // Create the directory if it doesn't exist
-        fs::create_dir_all(&copy_to_dir)
-            .context(format!("Failed to create directory {}", copy_to_dir.display()))?;
-        
-        // Get the pak filename and create the full destination path
-        let pak_filename = pak_path.file_name()
-            .context("Failed to get pak filename")?;
-        let copy_to_path = copy_to_dir.join(pak_filename);
-        
-        println!("Copying {} to {}", pak_path.display(), copy_to_path.display());
-        fs::copy(&pak_path, &copy_to_path).context("Failed to copy pak file")?;
+    for (value_path, branch) in values {
+        if value_path == provenance::WHOLE_FILE_KEY {
+            println!("{}: {}", file, branch);
+        } else {
+            println!("{}: {}", value_path, branch);
+        }
     }
 
     Ok(())
 }
 
-fn run() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+/// Runs the command. Returns true if some files couldn't be merged value by value
+fn run(args: Args) -> Result<bool, anyhow::Error> {
+    let config_path = PathBuf::from(args.config_file.as_deref().unwrap_or("config.toml"));
 
-    let config_path = match args.config_file {
-        None => {
-            let default_path = PathBuf::from("config.toml");
-            if !default_path.exists() {
-                std::fs::write(&default_path, DEFAULT_CONFIG_FILE)
-                    .context("Failed to write default config file")?;
+    // Running without a config (i.e. by double clicking the exe) creates the default one, so the next run can build
+    let is_build = matches!(args.command, None | Some(Command::Build));
+    if args.config_file.is_none() && is_build && !config_path.exists() {
+        fs::write(&config_path, config::DEFAULT_CONFIG_FILE)
+            .context("Failed to write default config file")?;
 
-                let absolute_path = fs::canonicalize(&default_path)
-                    .context("Failed to get absolute path of default config file")?;
-                println!("Created default config file: {}", absolute_path.display());
-            }
+        let absolute_path = fs::canonicalize(&config_path)
+            .context("Failed to get absolute path of default config file")?;
+        println!("Created default config file: {}", absolute_path.display());
+    }
 
-            default_path
-        }
-        Some(path) => PathBuf::from(path),
+    let builder = || -> Result<ModpackBuilder> {
+        let builder = ModpackBuilder::from_config_file(&config_path)?.force(args.force_staging);
+        let Some(profile) = &args.profile else {
+            return Ok(builder);
+        };
+
+        println!("Using profile {}", profile);
+        builder.profile(profile)
     };
 
-    create_modpack(&config_path)?;
+    match &args.command {
+        Some(Command::Init { force }) => init(&config_path, *force)?,
+        Some(Command::List) => list(&builder()?)?,
+        Some(Command::Build) | None => return create_modpack(&builder()?),
+        Some(Command::Conflicts) => return conflicts(&builder()?),
+        Some(Command::Diff { name }) => diff(&builder()?, name)?,
+        Some(Command::Clean) => clean(&builder()?)?,
+        Some(Command::Uninstall) => uninstall(&builder()?)?,
+        Some(Command::Blame { file, key }) => blame(&builder()?, file, key.as_deref())?,
+    }
 
-    Ok(())
+    Ok(false)
 }
 
-fn main() {
-    let result = run();
+/// Prints the library's progress messages and warnings as they are, since they're written to be read on their own
+struct ConsoleLogger;
 
-    if let Err(e) = &result {
-        eprintln!("Error: {:#}", e);
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        // Only this crate's messages, the dependencies' aren't meant for the user
+        metadata.level() <= log::Level::Info
+            && metadata.target().starts_with("unreal_pak_mod_manager")
     }
 
-    println!("Press Enter to exit...");
-    std::io::stdin().read_line(&mut String::new()).unwrap();
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+fn exit_code(error: &anyhow::Error) -> u8 {
+    match error.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::Config) => EXIT_CONFIG,
+        Some(ErrorKind::Extraction) => EXIT_EXTRACTION,
+        Some(ErrorKind::PakWrite) => EXIT_PAK_WRITE,
+        None => EXIT_ERROR,
+    }
+}
 
-    if result.is_err() {
-        std::process::exit(1);
+fn main() -> ExitCode {
+    let args = Args::parse();
+    // Can only fail when a logger is already set, which nothing else does
+    let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Info));
+
+    // Only wait when run interactively (i.e. by double clicking the exe), so the window doesn't close before the
+    // output can be read
+    let pause = !args.no_pause && std::io::stdin().is_terminal();
+
+    let code = match run(args) {
+        Ok(false) => 0,
+        Ok(true) => EXIT_UNRESOLVED_CONFLICTS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            exit_code(&e)
+        }
+    };
+
+    if pause {
+        println!("Press Enter to exit...");
+        // The exit code is already decided, so failing to wait doesn't change it
+        let _ = std::io::stdin().read_line(&mut String::new());
     }
+
+    ExitCode::from(code)
 }
@@ -1,16 +1,222 @@
-pub fn merge_json_strings(base: &str, ours: &str, theirs: &str) -> anyhow::Result<String> {
-    // Parse the JSON strings into Value objects
-    let mut base_json: serde_json::Value = serde_json::from_str(base)?;
-    let our_json: serde_json::Value = serde_json::from_str(ours)?;
-    let their_json: serde_json::Value = serde_json::from_str(theirs)?;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+/// A value that both sides of a three-way merge changed to different values
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ValueConflict {
+    /// Where the value lives in the file. This is a struct name path for cfg files, `[Section] Key` for
+    /// ini files and a JSON pointer for json files
+    pub path: String,
+    /// The value before either side changed it. None if the value did not exist
+    pub base: Option<String>,
+    /// Our value. None if we deleted it
+    pub ours: Option<String>,
+    /// Their value, which is the one that is kept. None if they deleted it
+    pub theirs: Option<String>,
+}
+
+/// Returns true if one JSON pointer is the same as, or a parent of, the other
+fn pointers_overlap(a: &str, b: &str) -> bool {
+    fn is_prefix(prefix: &str, path: &str) -> bool {
+        path == prefix || path.starts_with(&format!("{}/", prefix))
+    }
+
+    is_prefix(a, b) || is_prefix(b, a)
+}
+
+/// Flattens a JSON value into a map of JSON pointers to the values of its leaves
+pub fn json_values_by_pointer(json: &serde_json::Value) -> IndexMap<String, String> {
+    fn collect(json: &serde_json::Value, pointer: String, result: &mut IndexMap<String, String>) {
+        match json {
+            serde_json::Value::Object(map) if !map.is_empty() => {
+                for (key, value) in map {
+                    let escaped = key.replace('~', "~0").replace('/', "~1");
+                    collect(value, format!("{}/{}", pointer, escaped), result);
+                }
+            }
+            serde_json::Value::Array(values) if !values.is_empty() => {
+                for (idx, value) in values.iter().enumerate() {
+                    collect(value, format!("{}/{}", pointer, idx), result);
+                }
+            }
+            _ => {
+                result.insert(pointer, json.to_string());
+            }
+        }
+    }
+
+    let mut result = IndexMap::new();
+    collect(json, String::new(), &mut result);
+    result
+}
+
+/// Arrays that both sides only appended to are merged by combining the appended elements, rather than as edits to the
+/// same indices. The combined array is written to all three values, so the diffs leave it alone
+fn union_appends(
+    base: &mut serde_json::Value,
+    ours: &mut serde_json::Value,
+    theirs: &mut serde_json::Value,
+) {
+    use serde_json::Value;
+
+    match (base, ours, theirs) {
+        (Value::Object(base), Value::Object(ours), Value::Object(theirs)) => {
+            for (key, base_value) in base.iter_mut() {
+                if let (Some(our_value), Some(their_value)) =
+                    (ours.get_mut(key), theirs.get_mut(key))
+                {
+                    union_appends(base_value, our_value, their_value);
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(ours), Value::Array(theirs)) => {
+            let appended = |values: &Vec<Value>| {
+                values.len() > base.len() && values.starts_with(base.as_slice())
+            };
+
+            if appended(ours) && appended(theirs) {
+                let mut combined = ours.clone();
+                for value in &theirs[base.len()..] {
+                    if !ours[base.len()..].contains(value) {
+                        combined.push(value.clone());
+                    }
+                }
+
+                *base = combined.clone();
+                *ours = combined.clone();
+                *theirs = combined;
+            } else if ours.len() == base.len() && theirs.len() == base.len() {
+                for ((base_value, our_value), their_value) in
+                    base.iter_mut().zip(ours.iter_mut()).zip(theirs.iter_mut())
+                {
+                    union_appends(base_value, our_value, their_value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Three-way merge of JSON values. When both sides change the same value, theirs wins and the
+/// conflict is returned alongside the merged value
+pub fn merge_json_values(
+    base: &serde_json::Value,
+    our_json: &serde_json::Value,
+    their_json: &serde_json::Value,
+) -> anyhow::Result<(serde_json::Value, Vec<ValueConflict>)> {
+    let mut base_json = base.clone();
+    let mut our_json = our_json.clone();
+    let mut their_json = their_json.clone();
+    union_appends(&mut base_json, &mut our_json, &mut their_json);
 
     // Merge the JSON values
     let our_diff = json_patch::diff(&base_json, &our_json);
     let their_diff = json_patch::diff(&base_json, &their_json);
 
+    // Any location that both diffs touch and that ended up with different values is a conflict
+    let mut conflicts: Vec<ValueConflict> = Vec::new();
+    for their_op in their_diff.iter() {
+        let their_path = their_op.path().as_str();
+
+        for our_op in our_diff.iter() {
+            let our_path = our_op.path().as_str();
+
+            if !pointers_overlap(our_path, their_path) {
+                continue;
+            }
+
+            // Compare at the more specific of the two locations
+            let path = if our_path.len() > their_path.len() {
+                our_path
+            } else {
+                their_path
+            };
+
+            let our_value = our_json.pointer(path);
+            let their_value = their_json.pointer(path);
+
+            if our_value != their_value && !conflicts.iter().any(|c| c.path == path) {
+                conflicts.push(ValueConflict {
+                    path: path.to_string(),
+                    base: base_json.pointer(path).map(|v| v.to_string()),
+                    ours: our_value.map(|v| v.to_string()),
+                    theirs: their_value.map(|v| v.to_string()),
+                });
+            }
+        }
+    }
+
+    // Our changes to the locations they also changed are dropped, since theirs win there anyway and applying both would
+    // leave their changes pointing at values ours already removed or moved
+    let our_diff = json_patch::Patch(
+        our_diff
+            .0
+            .into_iter()
+            .filter(|our_op| {
+                !their_diff.iter().any(|their_op| {
+                    pointers_overlap(our_op.path().as_str(), their_op.path().as_str())
+                })
+            })
+            .collect(),
+    );
+
     json_patch::patch(&mut base_json, &our_diff)?;
     json_patch::patch(&mut base_json, &their_diff)?;
 
+    Ok((base_json, conflicts))
+}
+
+pub fn merge_json_strings(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+) -> anyhow::Result<(String, Vec<ValueConflict>)> {
+    // Parse the JSON strings into Value objects
+    let base_json: serde_json::Value = serde_json::from_str(base)?;
+    let our_json: serde_json::Value = serde_json::from_str(ours)?;
+    let their_json: serde_json::Value = serde_json::from_str(theirs)?;
+
+    let (merged, conflicts) = merge_json_values(&base_json, &our_json, &their_json)?;
+
     // Convert back to string
-    Ok(serde_json::to_string_pretty(&base_json)?)
+    Ok((serde_json::to_string_pretty(&merged)?, conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appended_array_elements_are_combined() {
+        let base = serde_json::json!({ "Items": [{ "Name": "Bandage" }], "Price": 10 });
+        let ours = serde_json::json!({ "Items": [{ "Name": "Bandage" }, { "Name": "Medkit" }], "Price": 10 });
+        let theirs = serde_json::json!({ "Items": [{ "Name": "Bandage" }, { "Name": "Vodka" }], "Price": 20 });
+
+        let (merged, conflicts) = merge_json_values(&base, &ours, &theirs).unwrap();
+
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+        assert_eq!(
+            merged,
+            serde_json::json!({
+                "Items": [{ "Name": "Bandage" }, { "Name": "Medkit" }, { "Name": "Vodka" }],
+                "Price": 20
+            })
+        );
+    }
+
+    #[test]
+    fn removals_and_edits_of_the_same_value_keep_theirs() {
+        let base = serde_json::json!({ "Stats": { "HP": 100, "Armor": 5 }, "Drops": [1, 2, 3] });
+        let ours = serde_json::json!({ "Drops": [1, 2, 30] });
+        let theirs = serde_json::json!({ "Stats": { "HP": 50, "Armor": 5 }, "Drops": [1, 2] });
+
+        let (merged, conflicts) = merge_json_values(&base, &ours, &theirs).unwrap();
+
+        assert_eq!(
+            merged,
+            serde_json::json!({ "Stats": { "HP": 50, "Armor": 5 }, "Drops": [1, 2] })
+        );
+        let paths: Vec<&str> = conflicts.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, ["/Stats/HP", "/Drops/2"]);
+    }
 }
@@ -0,0 +1,1463 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use git2::{Oid, Repository};
+use indexmap::IndexMap;
+use path_slash::PathExt as _;
+
+use crate::{
+    archive,
+    build_state::{self, BuildState, Checkpoint, ModState},
+    config::{DeployMode, OutputMode, UpmmConfig},
+    content_root,
+    error::ErrorKind,
+    formats::FormatRegistry,
+    git, install, iostore, pak, provenance,
+};
+
+/// The name used for the vanilla game files in commits and provenance
+pub const BASE_BRANCH_NAME: &str = "vanilla";
+
+/// A mod in the mods directory
+#[derive(Debug, Clone)]
+pub struct ModInfo {
+    pub path: PathBuf,
+    /// The file name of the mod, which is what its options are keyed by in the config
+    pub name: String,
+    /// The branch of the mod in the staging repository
+    pub branch: String,
+    /// The resolved priority of the mod. Mods are merged from the lowest to the highest priority
+    pub priority: i64,
+    /// Why the mod is skipped, if it's disabled in the config
+    pub disabled: Option<DisabledReason>,
+}
+
+/// Why a mod in the mods directory isn't merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisabledReason {
+    /// mods.<mod_name>.enabled is false
+    NotEnabled,
+    /// The mod is in the disabled list
+    DisabledList,
+}
+
+impl std::fmt::Display for DisabledReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotEnabled => write!(f, "enabled = false"),
+            Self::DisabledList => write!(f, "in the disabled list"),
+        }
+    }
+}
+
+/// A mod that is about to be merged
+struct ModInput {
+    info: ModInfo,
+    source_hash: String,
+    /// The .pak files the mod's files are extracted from: the mod itself, or the .pak files in its archive
+    paks: Vec<PathBuf>,
+    /// The loose files of directory and archive mods, along with where they go relative to the staging directory
+    loose_files: Vec<(PathBuf, PathBuf)>,
+    /// The .utoc files of the mod's IoStore containers, along with the path shown to the user
+    utocs: Vec<(PathBuf, PathBuf)>,
+    /// Whether the mod is a .zip that hasn't been extracted yet, so its files aren't known. Archives are only extracted
+    /// when their files are needed, see [`extract_archive`]
+    pending_archive: bool,
+}
+
+/// The result of merging every mod into the staging repository
+struct ProcessedMods {
+    /// The mod branches in the order they were merged
+    branches: Vec<String>,
+    /// The mod branches whose merge was reused from the last build
+    reused: Vec<String>,
+    /// file path -> the mod branches that changed it
+    mod_files: BTreeMap<String, Vec<String>>,
+    conflicts: Vec<git::FileConflicts>,
+    containers: Vec<iostore::Container>,
+    mount_points: IndexMap<String, Vec<String>>,
+}
+
+/// Returns the mods in the mods directory in the order they should be merged, including the disabled ones
+fn collect_mods(mods_dir: &Path, config: &UpmmConfig) -> Result<Vec<ModInfo>> {
+    let mut entries: Vec<_> = std::fs::read_dir(mods_dir)
+        .with_context(|| format!("Failed to read mods directory '{}'", mods_dir.display()))?
+        .filter_map(Result::ok)
+        .collect();
+
+    entries.retain(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            return true;
+        }
+
+        if pak::has_extension(&path, "pak") || pak::has_extension(&path, "zip") {
+            true
+        } else if pak::has_extension(&path, "utoc") {
+            // IoStore containers belong to the .pak with the same name, so they are only a mod of their own when there is no .pak
+            !path.with_extension("pak").exists()
+        } else if pak::has_extension(&path, "ucas") {
+            false
+        } else {
+            log::warn!(
+                "Skipping {}: mods must be a directory, .pak, .zip or .utoc/.ucas",
+                path.display()
+            );
+            false
+        }
+    });
+
+    // Sort entries by name, this will ensure decrease_health comes before decrease_health_again
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let disabled_list = config.disabled.as_deref().unwrap_or_default();
+
+    let mut mods = Vec::new();
+    for (idx, entry) in entries.iter().enumerate() {
+        let path = entry.path();
+        let name = path
+            .file_name()
+            .expect("should always be able to get the filename from the path")
+            .to_str()
+            .expect("should always be able to get the str from the filename")
+            .to_string();
+
+        // Disabled mods still take up their alphabetical priority, so disabling a mod doesn't reorder the others
+        let priority = config
+            .mod_config(&name)
+            .and_then(|v| v.priority)
+            .unwrap_or(idx as i64);
+
+        let disabled = if disabled_list.contains(&name) {
+            Some(DisabledReason::DisabledList)
+        } else if config.mod_config(&name).and_then(|v| v.enabled) == Some(false) {
+            Some(DisabledReason::NotEnabled)
+        } else {
+            None
+        };
+
+        mods.push(ModInfo {
+            branch: git::normalize_git_ref(&name),
+            path,
+            name,
+            priority,
+            disabled,
+        });
+    }
+
+    for name in disabled_list {
+        if !mods.iter().any(|m| m.name == *name) {
+            log::warn!(
+                "{} is in the disabled list but not in '{}'",
+                name,
+                mods_dir.display()
+            );
+        }
+    }
+
+    // Sort mods based on their priorities
+    mods.sort_by_key(|m| m.priority);
+
+    Ok(mods)
+}
+
+/// Whether a mod is a .pak file rather than an archive or a directory of loose files
+fn is_pak_file(path: &Path) -> bool {
+    pak::has_extension(path, "pak")
+}
+
+/// Finds the files of a mod. The content root of loose files is detected, see [`content_root::find`]. Archives are
+/// left for [`extract_archive`]
+fn mod_input(info: ModInfo, config: &UpmmConfig) -> Result<ModInput> {
+    let mod_config = config.mod_config(&info.name);
+    let aes_key = mod_config
+        .and_then(|v| v.aes_key.as_deref())
+        .or(config.aes_key.as_deref());
+    let root_override = mod_config.and_then(|v| v.root.as_deref());
+
+    let mut input = ModInput {
+        source_hash: build_state::source_hash(&info.path, aes_key, root_override)
+            .context(ErrorKind::Extraction)?,
+        paks: Vec::new(),
+        loose_files: Vec::new(),
+        utocs: Vec::new(),
+        pending_archive: false,
+        info,
+    };
+    let m = &input.info;
+
+    if m.path.is_dir() {
+        let root = content_root::find(&m.name, &m.path, root_override)?;
+
+        let mut files = Vec::new();
+        pak::collect_files(&m.path, &mut files)?;
+        files.sort();
+        input.loose_files = root.map_files(&m.name, files);
+    } else if pak::has_extension(&m.path, "zip") {
+        input.pending_archive = true;
+    } else {
+        if is_pak_file(&m.path) {
+            input.paks.push(m.path.clone());
+        }
+
+        let utoc_path = m.path.with_extension("utoc");
+        if utoc_path.exists() {
+            input.utocs.push((utoc_path.clone(), utoc_path));
+        }
+    }
+
+    Ok(input)
+}
+
+/// Extracts a .zip mod into `archives_dir` and finds its files. Does nothing for other mods or when the archive was
+/// already extracted
+fn extract_archive(input: &mut ModInput, config: &UpmmConfig, archives_dir: &Path) -> Result<()> {
+    if !input.pending_archive {
+        return Ok(());
+    }
+
+    let m = &input.info;
+    let root_override = config.mod_config(&m.name).and_then(|v| v.root.as_deref());
+    let archive = archive::extract_zip(&m.path, &archives_dir.join(&m.name))
+        .context(ErrorKind::Extraction)?;
+    let root = content_root::find(&m.name, &archive.dir, root_override)?;
+
+    input.loose_files = root.map_files(
+        &m.name,
+        archive
+            .loose_files
+            .iter()
+            .map(|file| archive.dir.join(file)),
+    );
+    input.utocs = archive
+        .utocs
+        .iter()
+        .map(|utoc_path| {
+            let in_archive = utoc_path
+                .strip_prefix(&archive.dir)
+                .expect("archive files are always inside the archive dir");
+            (utoc_path.clone(), m.path.join(in_archive))
+        })
+        .collect();
+    input.paks = archive.paks;
+    input.pending_archive = false;
+
+    Ok(())
+}
+
+/// Returns every file the mods add to the staging directory, keyed by its lowercase path so it can be matched
+/// against the game's paks
+fn vanilla_files(mods: &[ModInput], config: &UpmmConfig) -> Result<HashMap<String, PathBuf>> {
+    let mut files: HashMap<String, PathBuf> = HashMap::new();
+    for m in mods {
+        let aes_key = config.mod_aes_key(&m.info.name)?;
+
+        let mut mod_files: Vec<PathBuf> = m
+            .loose_files
+            .iter()
+            .map(|(_, staged_path)| staged_path.clone())
+            .collect();
+        for pak_path in &m.paks {
+            mod_files.extend(
+                pak::pak_file_paths(pak_path, aes_key.as_ref(), config.game_root())
+                    .context(ErrorKind::Extraction)?,
+            );
+        }
+
+        for file in mod_files {
+            let key = file
+                .to_slash()
+                .context("Failed to get slash")?
+                .to_lowercase();
+            files.entry(key).or_insert(file);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Copies a single file of a mod to `relative_path` in the staging directory
+fn copy_file(
+    path: &Path,
+    relative_path: &Path,
+    mod_name: &str,
+    repo: &Repository,
+    formats: &FormatRegistry,
+) -> Result<()> {
+    let repo_parent = repo
+        .path()
+        .parent()
+        .expect("should always be able to get the parent of the repo path");
+
+    pak::check_staged_path(mod_name, &path.display().to_string(), relative_path)?;
+
+    // Create parent directories
+    if let Some(parent) = repo_parent.join(relative_path).parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory '{}'", parent.display()))?;
+    }
+
+    log::info!("{}: Copying {}", mod_name, relative_path.display());
+
+    let content = formats.normalize(
+        relative_path,
+        &std::fs::read(path).context(format!("Failed to read file '{}'", path.display()))?,
+    )?;
+
+    std::fs::write(repo_parent.join(relative_path), content).context(format!(
+        "Failed to write file '{}'",
+        relative_path.display()
+    ))?;
+
+    Ok(())
+}
+
+/// Adds the files of a mod to the staging directory. Returns their paths relative to the staging directory and the
+/// mount points of the mod's .pak files
+fn import_mod(
+    input: &ModInput,
+    repo: &Repository,
+    config: &UpmmConfig,
+    formats: &FormatRegistry,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let m = &input.info;
+    let repo_parent = repo
+        .path()
+        .parent()
+        .expect("should always be able to get the parent of the repo path");
+    let mut files = Vec::new();
+    let mut mount_points = Vec::new();
+
+    if !input.paks.is_empty() {
+        let aes_key = config.mod_aes_key(&m.name)?;
+        for pak_path in &input.paks {
+            let unpacked = pak::unpak_pak(
+                pak_path,
+                repo_parent,
+                aes_key.as_ref(),
+                formats,
+                config.game_root(),
+            )
+            .context(ErrorKind::Extraction)?;
+
+            files.extend(unpacked.files);
+            mount_points.push(unpacked.mount_point);
+        }
+    }
+
+    for (path, staged_path) in &input.loose_files {
+        copy_file(path, staged_path, &m.name, repo, formats).context(ErrorKind::Extraction)?;
+        files.push(staged_path.clone());
+    }
+
+    // The same file can be in more than one of an archive's paks
+    files.sort();
+    files.dedup();
+
+    let files = files
+        .iter()
+        .map(|file| {
+            file.to_slash()
+                .map(|file| file.into_owned())
+                .context("Failed to get slash")
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((files, mount_points))
+}
+
+/// The contents of IoStore containers can't be extracted, but the assets in them are recorded so overlaps between mods
+/// can be reported
+fn list_containers(input: &ModInput, game_root: &str) -> Vec<iostore::Container> {
+    let m = &input.info;
+
+    input
+        .utocs
+        .iter()
+        .filter_map(|(read_path, utoc_path)| match iostore::list_files(read_path, game_root) {
+            Ok(files) => {
+                log::info!(
+                    "{}: Found {} IoStore assets in {}",
+                    m.name,
+                    files.len(),
+                    utoc_path.display()
+                );
+                Some(iostore::Container {
+                    branch: m.branch.clone(),
+                    utoc_path: utoc_path.clone(),
+                    files,
+                })
+            }
+            Err(e) => {
+                log::warn!(
+                    "{}: Failed to read IoStore assets, overlaps with other mods won't be reported: {:#}",
+                    m.name, e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+fn head_id(repo: &Repository) -> Result<Oid> {
+    Ok(repo.head()?.peel_to_commit()?.id())
+}
+
+/// Merges every mod into the staging repository
+///
+/// The files are kept in two branches: the originals branch holds the first version of every file (vanilla when base
+/// paks are set, otherwise the version of the first mod that added it) and master holds the merged files. Each mod gets
+/// a branch created from the originals branch with its changes, which is then merged into master. Since master is only
+/// ever built up one mod at a time, the merges of the mods before the first mod that changed since the last build are
+/// reused
+#[allow(clippy::too_many_arguments)]
+fn process_all_mods_dirs(
+    mods: &mut [ModInput],
+    archives_dir: &Path,
+    vanilla_files: &HashMap<String, PathBuf>,
+    base_paks: &[PathBuf],
+    repo: &Repository,
+    config: &UpmmConfig,
+    formats: &FormatRegistry,
+    previous: Option<BuildState>,
+    settings_hash: String,
+    provenance: &mut provenance::Provenance,
+) -> Result<(ProcessedMods, BuildState)> {
+    // The number of mods at the start of the merge order that are the same as in the last build
+    let reuse_count = previous.as_ref().map_or(0, |previous| {
+        mods.iter()
+            .zip(&previous.mods)
+            .take_while(|(m, state)| {
+                m.info.branch == state.branch && m.source_hash == state.source_hash
+            })
+            .count()
+    });
+
+    let (initial, start, previous_mods) = match previous {
+        Some(previous) => {
+            let checkpoint = match reuse_count {
+                0 => &previous.start,
+                n => &previous.mods[n - 1].checkpoint,
+            };
+
+            // Go back to where the first changed mod was merged and throw away everything after it
+            git::reset_branch(
+                repo,
+                git::ORIGINALS_BRANCH,
+                Oid::from_str(&checkpoint.originals)?,
+            )?;
+            git::reset_branch(repo, "master", Oid::from_str(&checkpoint.master)?)?;
+            git::force_checkout_branch(repo, "master")?;
+            for state in &previous.mods[reuse_count..] {
+                git::delete_branch(repo, &state.branch)?;
+            }
+
+            // The vanilla files are still attributed to the base
+            provenance.record_change(
+                repo,
+                Oid::from_str(&previous.initial)?,
+                Oid::from_str(&previous.start.master)?,
+                BASE_BRANCH_NAME,
+                formats,
+            )?;
+
+            (previous.initial, previous.start, previous.mods)
+        }
+        None => {
+            let initial = head_id(repo)?;
+            git::checkout_branch(repo, git::ORIGINALS_BRANCH)
+                .context("Failed to create originals branch")?;
+
+            if !base_paks.is_empty() {
+                // Commit the vanilla version of every file the mods touch so it's the common ancestor of every mod branch
+                let repo_parent = repo
+                    .path()
+                    .parent()
+                    .expect("should always be able to get the parent of the repo path");
+                let base_key = config.base_aes_key()?;
+                let count = pak::extract_base_files(
+                    base_paks,
+                    vanilla_files,
+                    repo_parent,
+                    base_key.as_ref(),
+                    formats,
+                    config.game_root(),
+                )
+                .context(ErrorKind::Extraction)?;
+                log::info!("Extracted {} base files from the game paks", count);
+
+                git::commit_files(repo, BASE_BRANCH_NAME, true)
+                    .context("Failed to commit base files")?;
+            }
+
+            git::checkout_branch(repo, "master").context("Failed to checkout master")?;
+            git::merge_added_files(repo, git::ORIGINALS_BRANCH)
+                .context("Failed to merge base files")?;
+            provenance.record_change(repo, initial, head_id(repo)?, BASE_BRANCH_NAME, formats)?;
+
+            (initial.to_string(), Checkpoint::of(repo)?, Vec::new())
+        }
+    };
+
+    let mut processed = ProcessedMods {
+        branches: Vec::new(),
+        reused: Vec::new(),
+        mod_files: BTreeMap::new(),
+        conflicts: Vec::new(),
+        containers: Vec::new(),
+        mount_points: IndexMap::new(),
+    };
+    let mut states: Vec<ModState> = Vec::new();
+    let mut before = Oid::from_str(&start.master)?;
+
+    for (idx, m) in mods.iter_mut().enumerate() {
+        let branch = m.info.branch.clone();
+
+        let state = if idx < reuse_count {
+            log::info!(
+                "{}: Unchanged since the last build, reusing its merge",
+                branch
+            );
+            previous_mods[idx].clone()
+        } else {
+            // First add untracked files to the originals branch
+            git::checkout_branch(repo, git::ORIGINALS_BRANCH)
+                .context("Failed to checkout originals branch")?;
+            let originals_before = head_id(repo)?;
+
+            // Mods that didn't change but come after one that did are restored from the last build rather than
+            // extracted again
+            let unchanged = previous_mods
+                .iter()
+                .find(|state| state.branch == branch && state.source_hash == m.source_hash);
+            let restored = unchanged.and_then(|state| {
+                let commit = Oid::from_str(&state.branch_commit).ok()?;
+                git::restore_files(repo, commit, &state.files).ok()?;
+                log::info!(
+                    "{}: Unchanged since the last build, restored {} files",
+                    branch,
+                    state.files.len()
+                );
+                Some((
+                    state.files.clone(),
+                    state.mount_points.clone(),
+                    state.containers.clone(),
+                ))
+            });
+
+            let (files, mount_points, containers) = match restored {
+                Some(restored) => restored,
+                None => {
+                    extract_archive(m, config, archives_dir)?;
+                    let (files, mount_points) = import_mod(m, repo, config, formats)?;
+                    (files, mount_points, list_containers(m, config.game_root()))
+                }
+            };
+
+            git::commit_files(repo, &m.info.name, true)
+                .context("Failed to commit untracked_files")?;
+            let mut changed = git::changed_files(repo, originals_before, head_id(repo)?)?;
+
+            // Now checkout branch for this mod and add tracked files
+            git::checkout_branch(repo, &m.info.name).context("Failed to checkout branch")?;
+            let branch_before = head_id(repo)?;
+            git::commit_files(repo, &m.info.name, false)
+                .context("Failed to commit tracked files")?;
+            let branch_commit = head_id(repo)?;
+            changed.extend(git::changed_files(repo, branch_before, branch_commit)?);
+
+            log::info!("{}: Merging with priority {}", branch, m.info.priority);
+
+            git::checkout_branch(repo, "master").context("Failed to checkout master")?;
+            git::merge_added_files(repo, git::ORIGINALS_BRANCH)
+                .context("Failed to add new files")?;
+            let conflicts = git::merge_branch(repo, &branch, git::MergeStrategy::Custom, formats)
+                .context("Failed to merge branch")?;
+
+            ModState {
+                branch: branch.clone(),
+                source_hash: m.source_hash.clone(),
+                files,
+                branch_commit: branch_commit.to_string(),
+                changed_files: changed,
+                conflicts,
+                containers,
+                mount_points,
+                checkpoint: Checkpoint::of(repo)?,
+            }
+        };
+
+        let after = Oid::from_str(&state.checkpoint.master)?;
+        provenance.record_change(repo, before, after, &branch, formats)?;
+        before = after;
+
+        for file in &state.changed_files {
+            processed
+                .mod_files
+                .entry(file.clone())
+                .or_default()
+                .push(branch.clone());
+        }
+        processed.conflicts.extend(state.conflicts.iter().cloned());
+        processed
+            .containers
+            .extend(state.containers.iter().cloned());
+        if !state.mount_points.is_empty() {
+            processed
+                .mount_points
+                .insert(branch.clone(), state.mount_points.clone());
+        }
+        processed.branches.push(branch.clone());
+        if idx < reuse_count {
+            processed.reused.push(branch.clone());
+        }
+
+        states.push(state);
+    }
+
+    let state = BuildState {
+        settings_hash,
+        initial,
+        start,
+        mods: states,
+    };
+
+    Ok((processed, state))
+}
+
+/// A .pak mod that is copied to copy_to_dir along with the modpack with deploy = "mod_paks"
+struct DeployedMod {
+    branch: String,
+    /// The mod's .pak and IoStore files, as (source, file name in copy_to_dir)
+    files: Vec<(PathBuf, String)>,
+}
+
+/// How the modpack is written
+struct PakOptions {
+    version: repak::Version,
+    compression: Option<repak::Compression>,
+    mount_point: String,
+    /// The directory in the staging directory that the mount point points to
+    mount_dir: PathBuf,
+}
+
+/// Packs the given files in the staging directory into a .pak file. Returns the paths of the packed files relative to
+/// the staging directory
+fn write_pak(
+    staging_dir: &Path,
+    pak_path: &Path,
+    pak_files: &[PathBuf],
+    options: &PakOptions,
+) -> Result<Vec<String>> {
+    let pak_name = pak_path
+        .file_name()
+        .context("Failed to get pak file name")?
+        .to_str()
+        .context("Failed to get file str")?
+        .to_owned();
+    let mut pak = repak::PakBuilder::new()
+        .compression(options.compression)
+        .writer(
+            BufWriter::new(
+                File::create(pak_path)
+                    .with_context(|| format!("Failed to create '{}'", pak_path.display()))?,
+            ),
+            options.version,
+            options.mount_point.clone(),
+            None,
+        );
+
+    let mut files = Vec::new();
+    for path in pak_files {
+        let file_path = path.strip_prefix(staging_dir)?;
+        let path_slash = file_path.to_slash().context("Failed to get slash")?;
+        let entry_path = file_path
+            .strip_prefix(&options.mount_dir)?
+            .to_slash()
+            .context("Failed to get slash")?;
+        log::info!("{}: Packing {}", pak_name, path_slash);
+        pak.write_file(
+            &entry_path,
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+        )
+        .with_context(|| format!("Failed to write {} to {}", path_slash, pak_name))?;
+        files.push(path_slash.into_owned());
+    }
+
+    pak.write_index()?;
+
+    Ok(files)
+}
+
+/// Warns about the .pak files in `dir` that load after the modpack. Paks load in the order of their names, and the files
+/// of the pak that loads last win, so these would override the merged files
+fn check_load_order(dir: &Path, pak_filename: &str) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut later: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| {
+            pak::has_extension(Path::new(name), "pak")
+                && name.to_lowercase() > pak_filename.to_lowercase()
+        })
+        .collect();
+    later.sort();
+
+    if !later.is_empty() {
+        log::warn!(
+            "Warning: these paks in {} load after {} and override its files, set pak_prefix (i.e. \"zzz_\") so the modpack loads last:",
+            dir.display(),
+            pak_filename
+        );
+        for name in later {
+            log::warn!("{}", name);
+        }
+    }
+}
+
+/// A file that more than one mod changed, so the mods had to be merged
+#[derive(Debug, Clone)]
+pub struct MergedFile {
+    /// The path of the file within the modpack
+    pub path: String,
+    /// The mod branches that changed the file, in merge order
+    pub branches: Vec<String>,
+}
+
+/// What merging the mods into the staging directory produced
+#[derive(Debug, Clone)]
+pub struct MergeReport {
+    /// The mod branches in the order they were merged
+    pub mods: Vec<String>,
+    /// The mod branches that hadn't changed since the last build, so their merge was reused
+    pub reused_mods: Vec<String>,
+    /// file path -> the mod branches that changed it, in merge order
+    pub mod_files: BTreeMap<String, Vec<String>>,
+    /// The files that more than one mod changed
+    pub merged_files: Vec<MergedFile>,
+    /// The values that more than one mod changed to different values
+    pub conflicts: Vec<git::FileConflicts>,
+    /// The IoStore containers that couldn't be merged and must stay installed alongside the modpack
+    pub containers: Vec<iostore::Container>,
+    /// The mount points of each mod's .pak files, keyed by mod branch. Mods without .pak files aren't included
+    pub mount_points: IndexMap<String, Vec<String>>,
+}
+
+impl MergeReport {
+    /// Whether any file couldn't be merged value by value, so it was merged line by line with the later mod's lines
+    /// winning
+    pub fn has_unresolved_conflicts(&self) -> bool {
+        self.conflicts.iter().any(|file| file.overwritten)
+    }
+}
+
+/// What a build produced
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    /// The .pak file that was created
+    pub pak_path: PathBuf,
+    /// Where the .pak file was copied to when copy_to_dir is set
+    pub copied_to: Option<PathBuf>,
+    /// The files of the .pak mods that were copied to copy_to_dir along with the modpack. Only set when deploy is
+    /// mod_paks
+    pub deployed_mods: Vec<PathBuf>,
+    /// The file recording which mod set each value, see [`provenance::Provenance`]
+    pub provenance_path: PathBuf,
+    /// Every file in the modpack
+    pub files: Vec<String>,
+    /// The mods that must stay installed alongside the modpack, because they changed files it doesn't contain. Only set
+    /// when output_mode is merged_only
+    pub required_mods: Vec<PathBuf>,
+    pub merge: MergeReport,
+}
+
+/// Builds a modpack from a config
+///
+/// ```no_run
+/// use unreal_pak_mod_manager::ModpackBuilder;
+///
+/// let report = ModpackBuilder::from_config_file("config.toml".as_ref())?.build()?;
+/// println!("Created {}", report.pak_path.display());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ModpackBuilder {
+    config: UpmmConfig,
+    /// The config file the config was read from, recorded in the install manifest
+    config_file: Option<PathBuf>,
+    config_dir: PathBuf,
+    formats: FormatRegistry,
+    incremental: bool,
+    force: bool,
+}
+
+impl ModpackBuilder {
+    /// Relative directories in the config are resolved against the current directory unless
+    /// [`ModpackBuilder::config_dir`] is set
+    pub fn new(config: UpmmConfig) -> Self {
+        Self {
+            config,
+            config_file: None,
+            config_dir: PathBuf::from("."),
+            formats: FormatRegistry::default(),
+            incremental: true,
+            force: false,
+        }
+    }
+
+    /// Reads the config file and resolves relative directories against the directory it's in
+    pub fn from_config_file(config_path: &Path) -> Result<Self> {
+        let config = UpmmConfig::from_file(config_path)?;
+        let config_dir = config_path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut builder = Self::new(config).config_dir(config_dir);
+        builder.config_file = Some(fs::canonicalize(config_path).unwrap_or(config_path.into()));
+
+        Ok(builder)
+    }
+
+    /// Applies the overrides of a profile in the config, see [`UpmmConfig::with_profile`]
+    pub fn profile(mut self, profile_name: &str) -> Result<Self> {
+        self.config = self.config.with_profile(profile_name)?;
+        Ok(self)
+    }
+
+    /// Sets the directory that relative directories in the config are resolved against
+    pub fn config_dir(mut self, config_dir: impl Into<PathBuf>) -> Self {
+        self.config_dir = config_dir.into();
+        self
+    }
+
+    /// Sets the mergers used for each kind of file. The formats table of the config is applied on top of these
+    pub fn formats(mut self, formats: FormatRegistry) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    /// Whether to reuse the staging directory of the last build, only merging the mods from the first one that changed
+    /// onward. Enabled by default
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Whether to delete the staging directory even when it wasn't created by this tool or contains the config or mods
+    /// directory. Disabled by default
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn config(&self) -> &UpmmConfig {
+        &self.config
+    }
+
+    pub fn staging_dir(&self) -> PathBuf {
+        self.config_dir.join(&self.config.staging_dir)
+    }
+
+    pub fn mods_dir(&self) -> PathBuf {
+        self.config_dir.join(&self.config.mods_dir)
+    }
+
+    pub fn pak_path(&self) -> PathBuf {
+        self.config_dir.join(self.config.pak_file_name())
+    }
+
+    /// The provenance sidecar is written next to the output pak
+    pub fn provenance_path(&self) -> PathBuf {
+        self.config_dir
+            .join(format!("{}.provenance.json", self.config.name))
+    }
+
+    /// The .pak files of the game that are used as the merge base. base_pak comes last so it takes priority
+    fn base_paks(&self) -> Result<Vec<PathBuf>> {
+        let mut base_paks = Vec::new();
+        if let Some(base_paks_dir) = &self.config.base_paks_dir {
+            let base_paks_dir = self.config_dir.join(base_paks_dir);
+            let mut dir_paks: Vec<PathBuf> = fs::read_dir(&base_paks_dir)
+                .with_context(|| {
+                    format!(
+                        "Failed to read base paks directory '{}'",
+                        base_paks_dir.display()
+                    )
+                })?
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| pak::has_extension(path, "pak"))
+                .collect();
+
+            dir_paks.sort();
+            base_paks.extend(dir_paks);
+        }
+
+        if let Some(base_pak) = &self.config.base_pak {
+            base_paks.push(self.config_dir.join(base_pak));
+        }
+
+        Ok(base_paks)
+    }
+
+    /// Returns the mods in the mods directory in the order they are merged. Disabled mods are included, see
+    /// [`ModInfo::disabled`]
+    pub fn list_mods(&self) -> Result<Vec<ModInfo>> {
+        collect_mods(&self.mods_dir(), &self.config)
+    }
+
+    /// Merges the mods without creating the .pak file. The merge happens in a temporary directory, so the staging
+    /// directory and provenance of the last build are left alone
+    pub fn merge(&self) -> Result<MergeReport> {
+        let scratch_dir = archive::TempDir::new("dry-run")?;
+        let (merge, _) = self.merge_mods(&scratch_dir.path().join("staging"))?;
+
+        Ok(merge)
+    }
+
+    /// Merges the mods into `full_staging_dir`, reusing the merges of the last build in it where possible
+    fn merge_mods(&self, full_staging_dir: &Path) -> Result<(MergeReport, provenance::Provenance)> {
+        let config = &self.config;
+        let full_mods_dir = self.mods_dir();
+
+        if !full_mods_dir.is_dir() {
+            return Err(anyhow!(
+                "Mods directory '{}' does not exist",
+                full_mods_dir.display()
+            )
+            .context(ErrorKind::Config));
+        }
+
+        if fs::read_dir(&full_mods_dir)?.next().is_none() {
+            return Err(
+                anyhow!("Mods directory '{}' is empty", full_mods_dir.display())
+                    .context(ErrorKind::Config),
+            );
+        }
+
+        let base_paks = self.base_paks().context(ErrorKind::Config)?;
+
+        let mut formats = self.formats.clone();
+        if let Some(overrides) = &config.formats {
+            formats
+                .apply_overrides(overrides)
+                .context("Invalid formats in config")
+                .context(ErrorKind::Config)?;
+        }
+
+        let mods = self.list_mods()?;
+
+        log::info!("Processing the mods in the following order:");
+        for m in &mods {
+            match m.disabled {
+                Some(reason) => {
+                    log::info!("{}: {} (skipped, {})", m.priority, m.path.display(), reason)
+                }
+                None => log::info!("{}: {}", m.priority, m.path.display()),
+            }
+        }
+
+        let mods: Vec<ModInfo> = mods.into_iter().filter(|m| m.disabled.is_none()).collect();
+        if mods.is_empty() {
+            return Err(
+                anyhow!("Every mod in '{}' is disabled", full_mods_dir.display())
+                    .context(ErrorKind::Config),
+            );
+        }
+
+        // Archives are extracted for the duration of the build, so their .pak files can be read like any other. They are
+        // only extracted once their files are needed, so the mods an incremental build reuses are never extracted
+        let archives_dir = archive::TempDir::new("archives")?;
+
+        let mut mods = mods
+            .into_iter()
+            .map(|info| mod_input(info, config))
+            .collect::<Result<Vec<_>>>()?;
+
+        let vanilla_files = if base_paks.is_empty() {
+            HashMap::new()
+        } else {
+            for m in &mut mods {
+                extract_archive(m, config, archives_dir.path())?;
+            }
+            vanilla_files(&mods, config)?
+        };
+
+        let mut vanilla_keys: Vec<String> = vanilla_files.keys().cloned().collect();
+        vanilla_keys.sort();
+        let settings_hash = build_state::settings_hash(
+            config.aes_key.as_deref(),
+            &format!("{:?}", formats),
+            config.game_root(),
+            &base_paks,
+            &vanilla_keys,
+        )?;
+
+        // Reuse the staging repository if it was created by a build with the same settings
+        let previous = self
+            .incremental
+            .then(|| BuildState::read(&full_staging_dir.join(".git")))
+            .flatten()
+            .filter(|state| state.settings_hash == settings_hash);
+        let existing_repo = previous
+            .as_ref()
+            .and_then(|_| Repository::open(full_staging_dir).ok());
+
+        let (repo, previous) = match existing_repo {
+            Some(repo) => (repo, previous),
+            None => {
+                // Delete the modpack directory if it exists
+                if full_staging_dir.exists() {
+                    self.check_staging_dir(full_staging_dir)?;
+                    fs::remove_dir_all(full_staging_dir)
+                        .with_context(|| "Failed to delete modpack directory")?;
+                }
+
+                fs::create_dir_all(full_staging_dir).with_context(|| {
+                    format!(
+                        "Failed to create modpack directory '{}'",
+                        full_staging_dir.display()
+                    )
+                })?;
+
+                let repo: Repository = git::init_repository(
+                    full_staging_dir
+                        .to_str()
+                        .context("Failed to get staging dir str")?,
+                )
+                .context("Failed to initialize modpack repository")?;
+
+                (repo, None)
+            }
+        };
+
+        // The state is only written back once every mod is merged, so a failed build is never reused
+        BuildState::remove(repo.path())?;
+
+        let mut provenance = provenance::Provenance::default();
+        let (processed, state) = process_all_mods_dirs(
+            &mut mods,
+            archives_dir.path(),
+            &vanilla_files,
+            &base_paks,
+            &repo,
+            config,
+            &formats,
+            previous,
+            settings_hash,
+            &mut provenance,
+        )
+        .with_context(|| "Failed to process all input directories")?;
+
+        state.write(repo.path())?;
+
+        let merged_files = processed
+            .mod_files
+            .iter()
+            .filter(|(_, branches)| branches.len() > 1)
+            .map(|(path, branches)| MergedFile {
+                path: path.clone(),
+                branches: branches.clone(),
+            })
+            .collect();
+
+        let report = MergeReport {
+            mods: processed.branches,
+            reused_mods: processed.reused,
+            mod_files: processed.mod_files,
+            merged_files,
+            conflicts: processed.conflicts,
+            containers: processed.containers,
+            mount_points: processed.mount_points,
+        };
+
+        Ok((report, provenance))
+    }
+
+    /// Merges every mod in the mods directory into a single .pak file
+    pub fn build(&self) -> Result<BuildReport> {
+        let full_staging_dir = self.staging_dir();
+
+        // The output options are checked before merging, so a typo doesn't cost a whole merge
+        let version = self.config.pak_version();
+        let compression = self.config.compression();
+        let output_mode = self.config.output_mode();
+        let deploy = self.config.deploy()?;
+        self.configured_mount_point()?;
+
+        let (merge, provenance) = self.merge_mods(&full_staging_dir)?;
+
+        let pak_path = self.pak_path();
+        let deployed = match deploy {
+            DeployMode::Modpack => Vec::new(),
+            DeployMode::ModPaks => self.deployed_mods()?,
+        };
+        let (pak_files, required_mods) = match (deploy, output_mode) {
+            // The deployed mods provide the files only they changed, so the modpack only needs the rest
+            (DeployMode::ModPaks, _) => {
+                let branches: HashSet<&String> = deployed.iter().map(|m| &m.branch).collect();
+                (
+                    self.patch_files(&merge, |branch| branches.contains(branch)),
+                    Vec::new(),
+                )
+            }
+            (DeployMode::Modpack, OutputMode::All) => {
+                let mut pak_files = Vec::new();
+                pak::collect_files(&full_staging_dir, &mut pak_files)?;
+                (pak_files, Vec::new())
+            }
+            // Only .pak mods can stay installed, the files of the other mods are packed into the modpack
+            (DeployMode::Modpack, OutputMode::MergedOnly) => {
+                let pak_mods = self.pak_mods()?;
+                let branches: HashSet<&String> = pak_mods.iter().map(|m| &m.branch).collect();
+                (
+                    self.patch_files(&merge, |branch| branches.contains(branch)),
+                    Self::required_mods(&merge, &pak_mods),
+                )
+            }
+        };
+        let (mount_point, mount_dir) = self.mount_point(&merge, &full_staging_dir, &pak_files)?;
+        let options = PakOptions {
+            version,
+            compression,
+            mount_point,
+            mount_dir,
+        };
+        let files = write_pak(&full_staging_dir, &pak_path, &pak_files, &options)
+            .context(ErrorKind::PakWrite)?;
+
+        log::info!("{} created successfully!", pak_path.display());
+
+        let provenance_path = self.provenance_path();
+        provenance.write(&provenance_path)?;
+
+        let mod_paks = deployed.into_iter().flat_map(|m| m.files).collect();
+        let (copied_to, deployed_mods) = self
+            .copy_pak(&pak_path, mod_paks)
+            .context(ErrorKind::PakWrite)?;
+
+        Ok(BuildReport {
+            pak_path,
+            copied_to,
+            deployed_mods,
+            provenance_path,
+            files,
+            required_mods,
+            merge,
+        })
+    }
+
+    /// Returns the files the modpack contains when the mods are installed alongside it: the files that more than one mod
+    /// changed, and the files of mods for which `separate` returns false since they aren't installed
+    fn patch_files(&self, merge: &MergeReport, separate: impl Fn(&String) -> bool) -> Vec<PathBuf> {
+        let staging_dir = self.staging_dir();
+        let pak_files: Vec<PathBuf> = merge
+            .mod_files
+            .iter()
+            .filter(|(_, branches)| branches.len() > 1 || !branches.iter().all(&separate))
+            .map(|(path, _)| staging_dir.join(path))
+            .collect();
+
+        if pak_files.is_empty() {
+            log::info!("No file was changed by more than one mod, the modpack is empty");
+        }
+
+        pak_files
+    }
+
+    /// Returns the .pak mods that changed files no other mod changed, which must stay installed with output_mode =
+    /// "merged_only"
+    fn required_mods(merge: &MergeReport, pak_mods: &[ModInfo]) -> Vec<PathBuf> {
+        let required_branches: HashSet<&String> = merge
+            .mod_files
+            .values()
+            .filter(|branches| branches.len() == 1)
+            .flatten()
+            .collect();
+
+        pak_mods
+            .iter()
+            .filter(|m| required_branches.contains(&m.branch))
+            .map(|m| m.path.clone())
+            .collect()
+    }
+
+    /// Returns the enabled mods that are .pak files, and so can be installed on their own
+    fn pak_mods(&self) -> Result<Vec<ModInfo>> {
+        let pak_mods = self
+            .list_mods()?
+            .into_iter()
+            .filter(|m| m.disabled.is_none() && is_pak_file(&m.path))
+            .collect();
+
+        Ok(pak_mods)
+    }
+
+    /// Returns the enabled .pak mods, which are copied to copy_to_dir with deploy = "mod_paks". Their file names start
+    /// with the modpack's name and the mod's place in the merge order, so they load in that order and before the modpack
+    fn deployed_mods(&self) -> Result<Vec<DeployedMod>> {
+        let mods: Vec<ModInfo> = self
+            .list_mods()?
+            .into_iter()
+            .filter(|m| m.disabled.is_none())
+            .collect();
+        let width = mods.len().to_string().len().max(3);
+
+        // Without _P, "<name>_" sorts after "<name>_P.pak"
+        let pak_file_name = self.config.pak_file_name();
+        let stem = pak_file_name.trim_end_matches(".pak");
+        let stem = stem
+            .strip_suffix("_P")
+            .or_else(|| stem.strip_suffix("_p"))
+            .unwrap_or(stem);
+
+        let mut deployed = Vec::new();
+        for (idx, m) in mods.iter().enumerate() {
+            if !is_pak_file(&m.path) {
+                log::info!(
+                    "{}: Not a .pak file, its files are added to the modpack instead",
+                    m.name
+                );
+                continue;
+            }
+
+            // IoStore containers are installed along with their .pak
+            let files = ["pak", "utoc", "ucas"]
+                .iter()
+                .map(|ext| m.path.with_extension(ext))
+                .filter(|path| path.exists())
+                .map(|path| {
+                    let file_name = path
+                        .file_name()
+                        .context("Failed to get mod file name")?
+                        .to_string_lossy()
+                        .into_owned();
+                    let deployed_name = format!("{}_{:0width$}_{}", stem, idx, file_name);
+                    Ok((path, deployed_name))
+                })
+                .collect::<Result<_>>()?;
+
+            deployed.push(DeployedMod {
+                branch: m.branch.clone(),
+                files,
+            });
+        }
+
+        Ok(deployed)
+    }
+
+    /// Returns mount_point from the config along with the directory in the staging directory it points to
+    fn configured_mount_point(&self) -> Result<Option<(String, PathBuf)>> {
+        let Some(mount_point) = &self.config.mount_point else {
+            return Ok(None);
+        };
+
+        let game_root = self.config.game_root();
+        let mount_dir = pak::resolve_pak_path(mount_point, game_root)
+            .with_context(|| {
+                format!(
+                    "mount_point '{}' doesn't resolve to a directory of the game root '{}'",
+                    mount_point, game_root
+                )
+            })
+            .context(ErrorKind::Config)?;
+
+        Ok(Some((mount_point.clone(), mount_dir)))
+    }
+
+    /// Returns the mount point of the modpack and the directory in the staging directory it points to. mount_point from
+    /// the config is used when set. Otherwise, when every mod's .pak used the same mount point and every file is inside
+    /// of it, that mount point is kept, falling back to the game root
+    fn mount_point(
+        &self,
+        merge: &MergeReport,
+        staging_dir: &Path,
+        files: &[PathBuf],
+    ) -> Result<(String, PathBuf)> {
+        if let Some((mount_point, mount_dir)) = self.configured_mount_point()? {
+            for file in files {
+                let relative_path = file.strip_prefix(staging_dir)?;
+                if !relative_path.starts_with(&mount_dir) {
+                    return Err(anyhow!(
+                        "{} is outside of mount_point '{}'",
+                        relative_path.display(),
+                        mount_point
+                    ))
+                    .context(ErrorKind::Config);
+                }
+            }
+
+            return Ok((mount_point, mount_dir));
+        }
+
+        let game_root = self.config.game_root();
+        let default = (game_root.to_string(), PathBuf::new());
+
+        let mut mount_points = merge.mount_points.values().flatten();
+        let Some(first) = mount_points.next() else {
+            return Ok(default);
+        };
+        if !mount_points.all(|mount_point| mount_point == first) {
+            return Ok(default);
+        }
+
+        let Some(mount_dir) = pak::resolve_pak_path(first, game_root) else {
+            return Ok(default);
+        };
+        if mount_dir.as_os_str().is_empty() {
+            return Ok(default);
+        }
+
+        let all_inside = files.iter().all(|file| {
+            file.strip_prefix(staging_dir)
+                .is_ok_and(|file| file.starts_with(&mount_dir))
+        });
+
+        if !all_inside {
+            return Ok(default);
+        }
+
+        log::info!("Keeping the mount point of the mods: {}", first);
+        Ok((first.clone(), mount_dir))
+    }
+
+    /// Copies the modpack and the deployed mods, as (source, file name), to copy_to_dir if it's set. Returns where the
+    /// modpack and the mods were copied to. The install manifest in copy_to_dir records them, so the next build only
+    /// replaces what this one installed, see [`install::InstallManifest`]
+    fn copy_pak(
+        &self,
+        pak_path: &Path,
+        mut files: Vec<(PathBuf, String)>,
+    ) -> Result<(Option<PathBuf>, Vec<PathBuf>)> {
+        let Some(copy_to_dir) = &self.config.copy_to_dir else {
+            return Ok((None, Vec::new()));
+        };
+        let copy_to_dir = PathBuf::from(copy_to_dir);
+
+        let pak_filename = pak_path
+            .file_name()
+            .context("Failed to get pak filename")?
+            .to_string_lossy()
+            .into_owned();
+        files.push((pak_path.to_path_buf(), pak_filename.clone()));
+
+        let mut installed = install::install(
+            &copy_to_dir,
+            &self.config.name,
+            self.config_file.as_deref(),
+            &files,
+        )?;
+        check_load_order(&copy_to_dir, &pak_filename);
+
+        let copied_to = installed.pop();
+        Ok((copied_to, installed))
+    }
+
+    /// Removes what the last build installed in copy_to_dir and restores the files it replaced. Returns false if nothing
+    /// was installed
+    pub fn uninstall(&self) -> Result<bool> {
+        let copy_to_dir = self
+            .config
+            .copy_to_dir
+            .as_ref()
+            .context("copy_to_dir isn't set in the config, so nothing was installed")
+            .context(ErrorKind::Config)?;
+
+        install::uninstall(Path::new(copy_to_dir), &self.config.name)
+    }
+
+    /// Returns the changes a mod made in the last build as a patch. New files are compared against nothing and
+    /// changed files against their vanilla version (or the version of the first mod that added them)
+    pub fn diff_mod(&self, name: &str) -> Result<String> {
+        let repo = Repository::open(self.staging_dir())
+            .context("Failed to open the staging directory, build the modpack first")?;
+        let state = BuildState::read(repo.path())
+            .context("Failed to read the state of the last build, build the modpack first")?;
+
+        let branch = git::normalize_git_ref(name);
+        let idx = state
+            .mods
+            .iter()
+            .position(|m| m.branch == branch)
+            .ok_or_else(|| anyhow!("{} was not part of the last build", name))?;
+
+        // The originals branch before the mod added its files
+        let base = match idx {
+            0 => &state.start.originals,
+            idx => &state.mods[idx - 1].checkpoint.originals,
+        };
+
+        Ok(git::diff_patch(
+            &repo,
+            Oid::from_str(base)?,
+            Oid::from_str(&state.mods[idx].branch_commit)?,
+        )?)
+    }
+
+    /// Checks that the staging directory is safe to delete: it must be empty or a repository created by a previous build,
+    /// and must not be or contain the config or mods directory. A typo in staging_dir (i.e. ".") would otherwise delete
+    /// the user's files. Skipped when [`ModpackBuilder::force`] is set
+    fn check_staging_dir(&self, staging_dir: &Path) -> Result<()> {
+        if self.force {
+            return Ok(());
+        }
+
+        let canonical_staging_dir = fs::canonicalize(staging_dir).with_context(|| {
+            format!(
+                "Failed to resolve staging directory '{}'",
+                staging_dir.display()
+            )
+        })?;
+
+        for (name, dir) in [("config", &self.config_dir), ("mods", &self.mods_dir())] {
+            // The config directory is empty when the config file is given without a directory
+            let inside = fs::canonicalize(Path::new(".").join(dir))
+                .is_ok_and(|dir| dir.starts_with(&canonical_staging_dir));
+            if inside {
+                return Err(anyhow!(
+                    "Refusing to delete staging directory '{}', it contains the {} directory. Check staging_dir in the \
+                     config or use --force-staging to delete it anyway",
+                    staging_dir.display(),
+                    name
+                ))
+                .context(ErrorKind::Config);
+            }
+        }
+
+        let is_empty = fs::read_dir(staging_dir).is_ok_and(|mut entries| entries.next().is_none());
+        if !is_empty && !git::is_staging_repository(staging_dir) {
+            return Err(anyhow!(
+                "Refusing to delete staging directory '{}', it wasn't created by a previous build. Check staging_dir in \
+                 the config or use --force-staging to delete it anyway",
+                staging_dir.display()
+            ))
+            .context(ErrorKind::Config);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the staging directory, so the next build starts from scratch. Returns false if there was nothing to delete
+    pub fn clean(&self) -> Result<bool> {
+        let staging_dir = self.staging_dir();
+        if !staging_dir.exists() {
+            return Ok(false);
+        }
+
+        self.check_staging_dir(&staging_dir)?;
+        fs::remove_dir_all(&staging_dir).with_context(|| {
+            format!(
+                "Failed to delete staging directory '{}'",
+                staging_dir.display()
+            )
+        })?;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use path_slash::PathExt as _;
+
+use crate::formats::FormatRegistry;
+
+/// Applies a path to a list of directories, returning None if it goes above the first one
+fn apply_path(mut dirs: Vec<String>, path: &str) -> Option<Vec<String>> {
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                dirs.pop()?;
+            }
+            name => dirs.push(name.to_string()),
+        }
+    }
+
+    Some(dirs)
+}
+
+/// Resolves a path within a pak (its mount point followed by an entry) against the mount point of the game root.
+/// Returns the path relative to the game root, or None if it's outside of it or goes through the directories the game
+/// is run from, whose names the game root doesn't tell
+pub(crate) fn resolve_pak_path(path: &str, game_root: &str) -> Option<PathBuf> {
+    let path = path.replace('\\', "/");
+    let game_root = game_root.replace('\\', "/");
+
+    // Stand-ins for the directories the game is run from, deep enough that going up never runs out of them
+    let depth = path.matches("..").count() + game_root.matches("..").count() + 1;
+    let exe_dir: Vec<String> = (0..depth).map(|idx| format!("<{}>", idx)).collect();
+
+    let root = apply_path(exe_dir.clone(), &game_root)?;
+    let resolved = if path.starts_with('/') {
+        // Absolute mount points (i.e. "/Stalker2/Content/") start from the game root
+        apply_path(root.clone(), &path)?
+    } else {
+        apply_path(exe_dir.clone(), &path)?
+    };
+
+    let relative = resolved.strip_prefix(root.as_slice())?;
+    if relative.iter().any(|name| exe_dir.contains(name)) {
+        return None;
+    }
+
+    Some(relative.iter().collect())
+}
+
+/// Whether the path has the extension. The case is ignored, since Windows file names are case insensitive
+pub(crate) fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+/// Checks that a file of a mod stays inside the staging directory once it's added to it. `relative_path` is where the
+/// file would be written, relative to the staging directory, and `entry` is the file as the mod names it
+pub(crate) fn check_staged_path(mod_name: &str, entry: &str, relative_path: &Path) -> Result<()> {
+    let mut components = relative_path.components();
+    let inside = components.clone().next().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)));
+    // The staging directory's .git directory holds the merge history, mods must never write into it
+    let into_git = relative_path
+        .components()
+        .next()
+        .is_some_and(|component| component.as_os_str().eq_ignore_ascii_case(".git"));
+
+    if !inside || into_git {
+        return Err(anyhow!(
+            "{}: {} would be written to '{}', which is outside of the staging directory",
+            mod_name,
+            entry,
+            relative_path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the flag in the pak footer that says whether the index is encrypted
+/// The footer layout changes between versions, but the flag is always the byte right before the magic
+fn pak_index_encrypted(path: &Path) -> Option<bool> {
+    const PAK_MAGIC: [u8; 4] = 0x5A6F12E1u32.to_le_bytes();
+
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(256))).ok()?;
+
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+    let magic_idx = tail.windows(4).rposition(|w| w == PAK_MAGIC)?;
+
+    magic_idx.checked_sub(1).map(|flag_idx| tail[flag_idx] == 1)
+}
+
+pub(crate) fn open_pak(path: &Path, key: Option<&aes::Aes256>) -> Result<repak::PakReader> {
+    let mut builder = repak::PakBuilder::new();
+    if let Some(key) = key {
+        builder = builder.key(key.clone());
+    }
+
+    let result = builder.reader(&mut std::io::BufReader::new(
+        File::open(path)
+            .with_context(|| format!("Failed to open pak file '{}'", path.display()))?,
+    ));
+
+    result.map_err(|e| {
+        let error = anyhow::Error::from(e);
+        match (pak_index_encrypted(path), key) {
+            (Some(true), None) => error.context(format!(
+                "'{}' is encrypted, set aes_key (or mods.<mod_name>.aes_key) to the key it was encrypted with",
+                path.display()
+            )),
+            (Some(true), Some(_)) => error.context(format!(
+                "'{}' is encrypted and could not be decrypted with the configured aes_key, the key is probably wrong",
+                path.display()
+            )),
+            _ => error.context(format!("Failed to read pak file '{}'", path.display())),
+        }
+    })
+}
+
+/// Returns the path of each file in the pak along with where it should be extracted to, relative to the staging directory
+/// When the mount point is outside of the game root the pak's files are skipped with a warning, but an entry that
+/// escapes the staging directory on its own (i.e. with `..`) is an error
+pub(crate) fn pak_entries(
+    pak_path: &Path,
+    pak: &repak::PakReader,
+    game_root: &str,
+) -> Result<Vec<(String, PathBuf)>> {
+    let pak_name = pak_path
+        .file_name()
+        .context("Failed to get pak file name")?
+        .to_string_lossy();
+    let mount_point = pak.mount_point();
+    if !mount_point.replace('\\', "/").starts_with(game_root) {
+        log::info!(
+            "{}: Mount point {} is not {}, resolving it against the game root",
+            pak_name,
+            mount_point,
+            game_root
+        );
+    }
+
+    if resolve_pak_path(mount_point, game_root).is_none() {
+        log::warn!(
+            "{}: Skipping every file, mount point {} doesn't resolve to a directory of the game root {}",
+            pak_name, mount_point, game_root
+        );
+        return Ok(Vec::new());
+    }
+
+    pak.files()
+        .into_iter()
+        .map(|entry_path| {
+            let full_path = format!("{}/{}", mount_point.trim_end_matches('/'), entry_path);
+            let relative_out_path = resolve_pak_path(&full_path, game_root).unwrap_or_default();
+            check_staged_path(&pak_name, &entry_path, &relative_out_path)?;
+
+            Ok((entry_path, relative_out_path))
+        })
+        .collect()
+}
+
+fn extract_pak_file(
+    pak_path: &Path,
+    pak: &repak::PakReader,
+    entry_path: &str,
+    output_dir: &Path,
+    relative_out_path: &Path,
+    formats: &FormatRegistry,
+) -> Result<()> {
+    let out_path = &output_dir.join(relative_out_path);
+
+    // Create parent directories
+    if let Some(parent) = out_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory '{}'", parent.display()))?;
+    }
+
+    // Extract the file to a string first
+    let mut content = Vec::new();
+    pak.read_file(
+        entry_path,
+        &mut std::io::BufReader::new(File::open(pak_path)?),
+        &mut content,
+    )?;
+
+    // Normalize and write the content
+    let normalized = formats.normalize(relative_out_path, &content)?;
+    fs::write(out_path, normalized).context(format!(
+        "failed to write to {}",
+        &out_path.to_str().context("Failed to get str from path")?
+    ))?;
+
+    Ok(())
+}
+
+/// The files extracted from a pak
+pub(crate) struct UnpackedPak {
+    /// The mount point the pak was created with
+    pub mount_point: String,
+    /// The extracted files, relative to the output directory
+    pub files: Vec<PathBuf>,
+}
+
+pub(crate) fn unpak_pak(
+    path: &Path,
+    output_dir: &Path,
+    key: Option<&aes::Aes256>,
+    formats: &FormatRegistry,
+    game_root: &str,
+) -> Result<UnpackedPak> {
+    let pak = open_pak(path, key)?;
+    let mut files = Vec::new();
+
+    // Extract each file
+    for (entry_path, relative_out_path) in pak_entries(path, &pak, game_root)? {
+        log::info!(
+            "{}: Extracting {}",
+            path.file_name()
+                .expect("should be able to get filename from path")
+                .to_str()
+                .expect("should be able to get str from filename"),
+            relative_out_path
+                .to_str()
+                .expect("should be able to get str from path"),
+        );
+
+        extract_pak_file(
+            path,
+            &pak,
+            &entry_path,
+            output_dir,
+            &relative_out_path,
+            formats,
+        )?;
+        files.push(relative_out_path);
+    }
+
+    Ok(UnpackedPak {
+        mount_point: pak.mount_point().to_string(),
+        files,
+    })
+}
+
+pub(crate) fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(format!("Failed to read dir {}", dir.display()))? {
+        let entry = entry;
+        let path = entry?.path();
+
+        if path.file_name().context("Failed to get filename")? == ".git" {
+            continue;
+        }
+
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the paths, relative to the staging directory, of every file the pak will add to the staging directory
+pub(crate) fn pak_file_paths(
+    path: &Path,
+    key: Option<&aes::Aes256>,
+    game_root: &str,
+) -> Result<Vec<PathBuf>> {
+    Ok(pak_entries(path, &open_pak(path, key)?, game_root)?
+        .into_iter()
+        .map(|(_, relative_out_path)| relative_out_path)
+        .collect())
+}
+
+/// Extracts the vanilla version of the given files from the game's paks into the staging directory
+/// Paths are matched case-insensitively, since that's how Unreal resolves them, but are written with the casing the mods use
+pub(crate) fn extract_base_files(
+    base_paks: &[PathBuf],
+    mod_files: &HashMap<String, PathBuf>,
+    output_dir: &Path,
+    key: Option<&aes::Aes256>,
+    formats: &FormatRegistry,
+    game_root: &str,
+) -> Result<usize> {
+    let paks = base_paks
+        .iter()
+        .map(|pak_path| {
+            open_pak(pak_path, key)
+                .with_context(|| format!("Failed to read base pak '{}'", pak_path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Later paks override earlier ones, so only remember the last pak that contains each file
+    let mut base_files: HashMap<&String, (usize, String)> = HashMap::new();
+    for (pak_idx, pak) in paks.iter().enumerate() {
+        for (entry_path, relative_out_path) in pak_entries(&base_paks[pak_idx], pak, game_root)? {
+            let key = relative_out_path
+                .to_slash()
+                .context("Failed to get slash")?
+                .to_lowercase();
+
+            if let Some((key, _)) = mod_files.get_key_value(&key) {
+                base_files.insert(key, (pak_idx, entry_path));
+            }
+        }
+    }
+
+    for (key, (pak_idx, entry_path)) in &base_files {
+        let pak_path = &base_paks[*pak_idx];
+        let relative_out_path = &mod_files[*key];
+
+        log::info!(
+            "{}: Extracting base {}",
+            pak_path
+                .file_name()
+                .expect("should be able to get filename from path")
+                .to_str()
+                .expect("should be able to get str from filename"),
+            relative_out_path.display(),
+        );
+
+        extract_pak_file(
+            pak_path,
+            &paks[*pak_idx],
+            entry_path,
+            output_dir,
+            relative_out_path,
+            formats,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to extract {} from base pak '{}'",
+                entry_path,
+                pak_path.display()
+            )
+        })?;
+    }
+
+    Ok(base_files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_points_resolve_against_the_game_root() {
+        let game_root = "../../../";
+
+        assert_eq!(
+            resolve_pak_path("../../../Stalker2/Content/a.cfg", game_root),
+            Some(PathBuf::from("Stalker2/Content/a.cfg"))
+        );
+        assert_eq!(
+            resolve_pak_path("/Stalker2/Content/a.cfg", game_root),
+            Some(PathBuf::from("Stalker2/Content/a.cfg"))
+        );
+        assert_eq!(resolve_pak_path("../../../../a.cfg", game_root), None);
+    }
+
+    #[test]
+    fn too_shallow_mount_points_are_not_resolved() {
+        let game_root = "../../../";
+
+        // These point into the directories the game is run from, which aren't known
+        assert_eq!(resolve_pak_path("a.cfg", game_root), None);
+        assert_eq!(resolve_pak_path("../../Stalker2/a.cfg", game_root), None);
+        assert_eq!(resolve_pak_path("", game_root), None);
+    }
+
+    #[test]
+    fn staged_paths_must_stay_inside_the_staging_directory() {
+        let check = |path: &str| check_staged_path("Mod.pak", path, Path::new(path));
+
+        assert!(check("Stalker2/Content/a.cfg").is_ok());
+        assert!(check("a.cfg").is_ok());
+        // A file named like .git further down is fine, only the staging directory's own .git is protected
+        assert!(check("Stalker2/.git").is_ok());
+
+        for path in [
+            "../a.cfg",
+            "Stalker2/../../a.cfg",
+            "/etc/passwd",
+            "",
+            ".git/config",
+            ".GIT/hooks/post-commit",
+            "./a.cfg",
+        ] {
+            assert!(check(path).is_err(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+use git2::{Oid, Repository};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::formats::FormatRegistry;
+
+/// The key used for files that can't be broken down into individual values
+pub const WHOLE_FILE_KEY: &str = "";
+
+/// Records which mod branch last set each value in the staged files. Values are identified by the
+/// same paths used in conflict reports: struct name paths for cfg files, `[Section] Key` for ini
+/// files and JSON pointers for json files
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Provenance {
+    /// file path -> value path -> branch
+    pub files: BTreeMap<String, IndexMap<String, String>>,
+}
+
+/// Breaks a file down into its individual values. Files without a merger (or that fail to parse)
+/// are treated as a single value
+fn values_by_path(
+    path: &str,
+    content: &[u8],
+    formats: &FormatRegistry,
+) -> IndexMap<String, String> {
+    let parsed = std::str::from_utf8(content).ok().and_then(|text| {
+        formats
+            .get(path)
+            .and_then(|merger| merger.string_values_by_path(path, text).ok())
+    });
+
+    parsed.unwrap_or_else(|| {
+        let id = Oid::hash_object(git2::ObjectType::Blob, content)
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+
+        IndexMap::from([(WHOLE_FILE_KEY.to_string(), id)])
+    })
+}
+
+impl Provenance {
+    /// Attributes every value that changed between two commits to the given branch
+    pub fn record_change(
+        &mut self,
+        repo: &Repository,
+        before: Oid,
+        after: Oid,
+        branch: &str,
+        formats: &FormatRegistry,
+    ) -> anyhow::Result<()> {
+        if before == after {
+            return Ok(());
+        }
+
+        let old_tree = repo.find_commit(before)?.tree()?;
+        let new_tree = repo.find_commit(after)?.tree()?;
+        let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+        for delta in diff.deltas() {
+            let new_file = delta.new_file();
+            let path = new_file
+                .path()
+                .or_else(|| delta.old_file().path())
+                .and_then(|p| p.to_str())
+                .context("Failed to get path of changed file")?
+                .replace('\\', "/");
+
+            if delta.status() == git2::Delta::Deleted {
+                self.files.remove(&path);
+                continue;
+            }
+
+            let old_values = if delta.old_file().id().is_zero() {
+                IndexMap::new()
+            } else {
+                values_by_path(
+                    &path,
+                    repo.find_blob(delta.old_file().id())?.content(),
+                    formats,
+                )
+            };
+            let new_values =
+                values_by_path(&path, repo.find_blob(new_file.id())?.content(), formats);
+
+            let file = self.files.entry(path).or_default();
+            file.retain(|key, _| new_values.contains_key(key));
+
+            for (key, value) in &new_values {
+                if old_values.get(key) != Some(value) {
+                    file.insert(key.clone(), branch.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the branch that set each value in the file. If a key is given, only that value and
+    /// the values nested under it are returned. Keys match whole path segments, so `Item/Max`
+    /// doesn't return `Item/MaxHP`, and an ini section like `[Core]` returns the values in it
+    pub fn blame(&self, file: &str, key: Option<&str>) -> Vec<(&String, &String)> {
+        let file = file.replace('\\', "/");
+
+        let Some(values) = self.files.get(file.trim_start_matches('/')) else {
+            return Vec::new();
+        };
+
+        values
+            .iter()
+            .filter(|(value_path, _)| {
+                key.is_none_or(|key| {
+                    let is_section = key.starts_with('[') && key.ends_with(']');
+                    *value_path == key
+                        || value_path.starts_with(&format!("{}/", key))
+                        || (is_section && value_path.starts_with(&format!("{} ", key)))
+                })
+            })
+            .collect()
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write provenance file '{}'", path.display()))
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read provenance file '{}'", path.display()))?;
+
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git;
+
+    /// Writes the files into the repository and commits them, returning the new commit
+    fn commit(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let dir = repo.workdir().unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        git::commit_files(repo, "test", false).unwrap();
+        repo.head().unwrap().peel_to_commit().unwrap().id()
+    }
+
+    #[test]
+    fn changed_values_are_attributed_to_the_branch() {
+        let dir = std::env::temp_dir().join(format!("upmm_provenance_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let repo = git::init_repository(dir.to_str().unwrap()).unwrap();
+        let formats = FormatRegistry::default();
+        let mut provenance = Provenance::default();
+
+        let initial = repo.head().unwrap().peel_to_commit().unwrap().id();
+        let first = commit(
+            &repo,
+            &[("Game.ini", "[Core]\nA=1\nB=1\n"), ("Data.bin", "1")],
+        );
+        provenance
+            .record_change(&repo, initial, first, "mod_a", &formats)
+            .unwrap();
+
+        let second = commit(&repo, &[("Game.ini", "[Core]\nA=1\nB=2\nC=2\n")]);
+        provenance
+            .record_change(&repo, first, second, "mod_b", &formats)
+            .unwrap();
+
+        let branches = |file: &str| -> Vec<(String, String)> {
+            provenance
+                .blame(file, None)
+                .into_iter()
+                .map(|(key, branch)| (key.clone(), branch.clone()))
+                .collect()
+        };
+        assert_eq!(
+            branches("Game.ini"),
+            [
+                ("[Core] A".to_string(), "mod_a".to_string()),
+                ("[Core] B".to_string(), "mod_b".to_string()),
+                ("[Core] C".to_string(), "mod_b".to_string()),
+            ]
+        );
+        // Files without a merger are a single value
+        assert_eq!(
+            branches("/Data.bin"),
+            [(WHOLE_FILE_KEY.to_string(), "mod_a".to_string())]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_match_whole_path_segments() {
+        let mut provenance = Provenance::default();
+        provenance.files.insert(
+            "Item.cfg".to_string(),
+            IndexMap::from(
+                [
+                    "Item/Max",
+                    "Item/Max/Sub",
+                    "Item/MaxHP",
+                    "Items/Max",
+                    "Item Extra",
+                    "[Core] A",
+                    "[Core] AB",
+                    "[CoreX] A",
+                ]
+                .map(|key| (key.to_string(), format!("mod of {}", key))),
+            ),
+        );
+        let keys = |key: &str| -> Vec<&str> {
+            provenance
+                .blame("Item.cfg", Some(key))
+                .into_iter()
+                .map(|(key, _)| key.as_str())
+                .collect()
+        };
+
+        assert_eq!(keys("Item/Max"), ["Item/Max", "Item/Max/Sub"]);
+        assert_eq!(keys("Item"), ["Item/Max", "Item/Max/Sub", "Item/MaxHP"]);
+        assert_eq!(keys("Item/Ma"), Vec::<&str>::new());
+        assert_eq!(keys("[Core] A"), ["[Core] A"]);
+        assert_eq!(keys("[Core]"), ["[Core] A", "[Core] AB"]);
+    }
+}
@@ -1,13 +1,15 @@
-use crate::merge;
+use crate::merge::ValueConflict;
+use indexmap::IndexMap;
 use nom::{
     bytes::complete::{tag, take_till},
-    character::complete::{multispace0, not_line_ending},
+    character::complete::{multispace0, space0},
     IResult,
 };
 use serde::Deserialize;
 use serde::Serialize;
 use slotmap::DefaultKey;
 use slotmap::SlotMap;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,6 +18,12 @@ pub struct Stalker2CfgStruct {
     pub meta: String,
     pub values: Vec<Stalker2CfgValue>,
     pub parent: Option<DefaultKey>,
+    /// Comments, blank lines and unrecognized lines between the last value and `struct.end`
+    pub end_leading_lines: Vec<String>,
+    /// The whitespace before `struct.end`
+    pub end_indent: String,
+    /// The whitespace and comment after `struct.end`
+    pub end_trailing: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,6 +31,14 @@ pub struct Stalker2CfgValue {
     pub name: String,
     pub value: Option<String>,
     pub struct_key: Option<DefaultKey>,
+    /// Comments, blank lines and unrecognized lines that come before this value
+    pub leading_lines: Vec<String>,
+    /// The whitespace before the name
+    pub indent: String,
+    /// Everything between the name and the value, i.e. ` = ` for values or ` : ` for structs
+    pub separator: String,
+    /// The whitespace and comment after the value (or after the meta for structs)
+    pub trailing: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,58 +46,150 @@ pub struct Stalker2Cfg {
     name: String,
     structs: SlotMap<DefaultKey, Stalker2CfgStruct>,
     root_values: Vec<Stalker2CfgValue>,
+    /// Comments, blank lines and unrecognized lines after the last value
+    trailing_lines: Vec<String>,
+    /// Either "\n" or "\r\n", based on what the file used. Every line is written with it, so a file that mixes both is
+    /// written with "\r\n" throughout
+    line_ending: String,
+    /// Whether the file ended with a line ending
+    final_newline: bool,
+    /// Whether the file started with a UTF-8 byte order mark
+    bom: bool,
 }
 
 impl Display for Stalker2Cfg {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines: Vec<String> = Vec::new();
+
         for value in &self.root_values {
-            if let Some(value_str) = &value.value {
-                writeln!(f, "{} = {}", value.name, value_str)?;
-            } else if let Some(struct_key) = value.struct_key {
-                write!(f, "{}", self.struct_to_string(struct_key, 0))?;
-            }
+            self.value_lines(value, &mut lines);
+        }
+        lines.extend(self.trailing_lines.iter().cloned());
+
+        if self.bom {
+            write!(f, "\u{feff}")?;
+        }
+
+        write!(f, "{}", lines.join(&self.line_ending))?;
+
+        if self.final_newline && !lines.is_empty() {
+            write!(f, "{}", self.line_ending)?;
         }
+
         Ok(())
     }
 }
 
+/// Splits the text after a value into the value itself and the trailing whitespace and `//` comment
+fn split_trailing(text: &str) -> (&str, &str) {
+    let mut in_quotes = false;
+    let mut comment_start = text.len();
+
+    for (idx, c) in text.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '/' if !in_quotes && text[idx..].starts_with("//") => {
+                comment_start = idx;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let value = text[..comment_start].trim_end();
+    (value, &text[value.len()..])
+}
+
+/// Splits a name from the whitespace that follows it, which is part of the separator
+fn split_name(name: &str) -> (&str, &str) {
+    let trimmed = name.trim_end();
+    (trimmed, &name[trimmed.len()..])
+}
+
+impl Stalker2CfgValue {
+    /// Creates a value laid out the same way as this one
+    fn with_layout(&self, value: Option<String>, struct_key: Option<DefaultKey>) -> Self {
+        Self {
+            name: self.name.clone(),
+            value,
+            struct_key,
+            leading_lines: self.leading_lines.clone(),
+            indent: self.indent.clone(),
+            separator: self.separator.clone(),
+            trailing: self.trailing.clone(),
+        }
+    }
+}
+
+impl Stalker2CfgStruct {
+    /// Creates an empty struct laid out the same way as this one
+    fn with_layout(&self, meta: String, parent: Option<DefaultKey>) -> Self {
+        Self {
+            name: self.name.clone(),
+            meta,
+            values: Vec::new(),
+            parent,
+            end_leading_lines: self.end_leading_lines.clone(),
+            end_indent: self.end_indent.clone(),
+            end_trailing: self.end_trailing.clone(),
+        }
+    }
+}
+
 impl Stalker2Cfg {
-    fn struct_to_string(&self, struct_key: DefaultKey, indent: usize) -> String {
-        let struct_value = self
-            .structs
-            .get(struct_key)
-            .expect("The given struct key does not exist");
-
-        let mut result = String::new();
-
-        std::fmt::write(
-            &mut result,
-            format_args!(
-                "{}{}\n",
-                " ".repeat(indent),
-                format!("{} : struct.begin{}", struct_value.name, struct_value.meta)
-            ),
-        )
-        .expect("Error writing to string");
-
-        for cfg_value in &struct_value.values {
-            if let Some(value) = &cfg_value.value {
-                std::fmt::write(
-                    &mut result,
-                    format_args!("{}{} = {}\n", " ".repeat(indent + 3), cfg_value.name, value),
-                )
-                .expect("Error writing to string");
-            } else if let Some(nested_key) = cfg_value.struct_key {
-                result.push_str(&self.struct_to_string(nested_key, indent + 3));
+    /// Adds the lines of a value (and any nested structs) exactly as they appeared in the original file
+    fn value_lines(&self, value: &Stalker2CfgValue, lines: &mut Vec<String>) {
+        lines.extend(value.leading_lines.iter().cloned());
+
+        if let Some(value_str) = &value.value {
+            lines.push(format!(
+                "{}{}{}{}{}",
+                value.indent, value.name, value.separator, value_str, value.trailing
+            ));
+        } else if let Some(struct_key) = value.struct_key {
+            let struct_value = self
+                .structs
+                .get(struct_key)
+                .expect("The given struct key does not exist");
+
+            lines.push(format!(
+                "{}{}{}struct.begin{}{}",
+                value.indent, value.name, value.separator, struct_value.meta, value.trailing
+            ));
+
+            for nested in &struct_value.values {
+                self.value_lines(nested, lines);
             }
+
+            lines.extend(struct_value.end_leading_lines.iter().cloned());
+            lines.push(format!(
+                "{}struct.end{}",
+                struct_value.end_indent, struct_value.end_trailing
+            ));
         }
+    }
+
+    /// Flattens the cfg into a map of name paths (i.e. `Bloodsucker/VitalParams/MaxHP`) to values.
+    /// Structs are included with their `struct.begin` meta as the value
+    pub fn values_by_path(&self) -> IndexMap<String, String> {
+        fn collect(
+            cfg: &Stalker2Cfg,
+            values: &[Stalker2CfgValue],
+            path: &str,
+            result: &mut IndexMap<String, String>,
+        ) {
+            for (key, value) in sibling_keys(values).iter().zip(values.iter()) {
+                let value_path = join_path(path, key);
+                result.insert(value_path.clone(), display_value(cfg, value));
 
-        std::fmt::write(
-            &mut result,
-            format_args!("{}{}\n", " ".repeat(indent), "struct.end"),
-        )
-        .expect("Error writing to string");
+                if let Some(nested) = struct_values(cfg, value) {
+                    collect(cfg, nested, &value_path, result);
+                }
+            }
+        }
 
+        let mut result = IndexMap::new();
+        collect(self, &self.root_values, "", &mut result);
         result
     }
 
@@ -91,72 +199,120 @@ impl Stalker2Cfg {
         let mut current_struct_key: Option<DefaultKey> = None;
         let mut struct_depth = 0;
         let mut line_number: i32 = 0;
+        let mut leading_lines: Vec<String> = Vec::new();
+
+        let bom = cfg_str.starts_with('\u{feff}');
+        let cfg_str = cfg_str.trim_start_matches('\u{feff}');
+
+        // Parser combinators. Each of them returns the indent of the line and the whitespace and comment after it
+        // so the line can be printed exactly as it was
+        struct Line<'a> {
+            indent: &'a str,
+            name: &'a str,
+            separator: String,
+            value: &'a str,
+            trailing: &'a str,
+        }
 
-        // Parser combinators
-        fn struct_begin(input: &str) -> IResult<&str, (String, String)> {
-            let (input, _) = multispace0(input)?;
+        fn struct_begin(input: &str) -> IResult<&str, Line<'_>> {
+            let (input, indent) = multispace0(input)?;
             let (input, name) = take_till(|c: char| c == ':')(input)?;
             let (input, _) = tag(":")(input)?;
-            let (input, _) = multispace0(input)?;
+            let (input, space) = multispace0(input)?;
             let (input, _) = tag("struct.begin")(input)?;
-            let (input, meta) = not_line_ending(input)?;
-            Ok((input, (name.trim().to_string(), meta.to_string())))
+            let (name, name_space) = split_name(name);
+            let (meta, trailing) = split_trailing(input);
+            Ok((
+                "",
+                Line {
+                    indent,
+                    name,
+                    separator: format!("{}:{}", name_space, space),
+                    value: meta,
+                    trailing,
+                },
+            ))
         }
 
-        fn struct_end(input: &str) -> IResult<&str, ()> {
-            let (input, _) = multispace0(input)?;
+        fn struct_end(input: &str) -> IResult<&str, Line<'_>> {
+            let (input, indent) = multispace0(input)?;
             let (input, _) = tag("struct.end")(input)?;
-            Ok((input, ()))
+            let (_, trailing) = split_trailing(input);
+            Ok((
+                "",
+                Line {
+                    indent,
+                    name: "",
+                    separator: String::new(),
+                    value: "",
+                    trailing,
+                },
+            ))
         }
 
-        fn value_line(input: &str) -> IResult<&str, (String, String)> {
-            let (input, _) = multispace0(input)?;
+        fn value_line(input: &str) -> IResult<&str, Line<'_>> {
+            let (input, indent) = multispace0(input)?;
             let (input, name) = take_till(|c: char| c == '=')(input)?;
             let (input, _) = tag("=")(input)?;
-            let (input, _) = multispace0(input)?;
-            let (input, value) = not_line_ending(input)?;
-            Ok((input, (name.trim().to_string(), value.to_string())))
+            let (input, space) = space0(input)?;
+            let (name, name_space) = split_name(name);
+            let (value, trailing) = split_trailing(input);
+            Ok((
+                "",
+                Line {
+                    indent,
+                    name,
+                    separator: format!("{}={}", name_space, space),
+                    value,
+                    trailing,
+                },
+            ))
         }
 
         for line in cfg_str.lines() {
             line_number += 1;
 
-            if line.trim().starts_with("//") {
+            if line.trim().is_empty() || line.trim().starts_with("//") {
+                leading_lines.push(line.to_string());
                 continue;
             }
 
-            if let Ok((_, (name, meta))) = struct_begin(line) {
+            if let Ok((_, begin)) = struct_begin(line) {
                 struct_depth += 1;
                 let struct_key = structs.insert(Stalker2CfgStruct {
-                    name: name.clone(),
-                    meta,
+                    name: begin.name.to_string(),
+                    meta: begin.value.to_string(),
                     values: Vec::new(),
                     parent: current_struct_key,
+                    end_leading_lines: Vec::new(),
+                    end_indent: String::new(),
+                    end_trailing: String::new(),
                 });
 
-                if current_struct_key.is_none() {
-                    root_values.push(Stalker2CfgValue {
-                        name,
-                        value: None,
-                        struct_key: Some(struct_key),
-                    });
-                } else {
-                    let current_struct = structs
-                        .get_mut(current_struct_key.expect("We handle none case above"))
-                        .expect("Structs are never deleted");
-
-                    current_struct.values.push(Stalker2CfgValue {
-                        name,
-                        value: None,
-                        struct_key: Some(struct_key),
-                    });
+                let value = Stalker2CfgValue {
+                    name: begin.name.to_string(),
+                    value: None,
+                    struct_key: Some(struct_key),
+                    leading_lines: std::mem::take(&mut leading_lines),
+                    indent: begin.indent.to_string(),
+                    separator: begin.separator,
+                    trailing: begin.trailing.to_string(),
+                };
+
+                match current_struct_key {
+                    None => root_values.push(value),
+                    Some(current_struct_key) => structs
+                        .get_mut(current_struct_key)
+                        .expect("Structs are never deleted")
+                        .values
+                        .push(value),
                 }
 
                 current_struct_key = Some(struct_key);
                 continue;
             }
 
-            if struct_end(line).is_ok() {
+            if let Ok((_, end)) = struct_end(line) {
                 struct_depth -= 1;
                 if struct_depth < 0 {
                     return Err(anyhow::anyhow!(
@@ -171,32 +327,14 @@ impl Stalker2Cfg {
                     ))
                     .expect("Structs are never deleted");
 
+                current_struct.end_leading_lines = std::mem::take(&mut leading_lines);
+                current_struct.end_indent = end.indent.to_string();
+                current_struct.end_trailing = end.trailing.to_string();
+
                 current_struct_key = current_struct.parent;
                 continue;
             }
 
-            if let Ok((_, (name, value))) = value_line(line) {
-                if current_struct_key.is_none() {
-                    root_values.push(Stalker2CfgValue {
-                        name,
-                        value: Some(value),
-                        struct_key: None,
-                    });
-                } else {
-                    let current_struct = structs
-                        .get_mut(current_struct_key.expect(
-                            "By the time we get to a value, we should always have a current struct key",
-                        ))
-                        .expect("Structs are never deleted");
-
-                    current_struct.values.push(Stalker2CfgValue {
-                        name,
-                        value: Some(value),
-                        struct_key: None,
-                    });
-                }
-            }
-
             if line.contains("struct.begin") {
                 return Err(anyhow::anyhow!(
                     "Unprocessed struct.begin statement at line {}: {}",
@@ -212,6 +350,33 @@ impl Stalker2Cfg {
                     line
                 ));
             }
+
+            let Ok((_, parsed)) = value_line(line) else {
+                // Keep lines we don't understand so the file can be printed exactly as it was
+                leading_lines.push(line.to_string());
+                continue;
+            };
+
+            let value = Stalker2CfgValue {
+                name: parsed.name.to_string(),
+                value: Some(parsed.value.to_string()),
+                struct_key: None,
+                leading_lines: std::mem::take(&mut leading_lines),
+                indent: parsed.indent.to_string(),
+                separator: parsed.separator,
+                trailing: parsed.trailing.to_string(),
+            };
+
+            match current_struct_key {
+                None => root_values.push(value),
+                Some(current_struct_key) => structs
+                    .get_mut(current_struct_key)
+                    .expect(
+                        "By the time we get to a value, we should always have a current struct key",
+                    )
+                    .values
+                    .push(value),
+            }
         }
 
         if struct_depth > 0 {
@@ -225,20 +390,554 @@ impl Stalker2Cfg {
             name,
             root_values,
             structs,
+            trailing_lines: leading_lines,
+            line_ending: if cfg_str.contains("\r\n") {
+                "\r\n".to_string()
+            } else {
+                "\n".to_string()
+            },
+            final_newline: cfg_str.ends_with('\n'),
+            bom,
         })
     }
 }
 
+/// Builds the identity of each value among its siblings. Values are identified by name, with
+/// repeated names (such as `[*]` array entries) told apart by their occurrence index
+fn sibling_keys(values: &[Stalker2CfgValue]) -> Vec<String> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+
+    values
+        .iter()
+        .map(|value| {
+            let count = seen.entry(value.name.as_str()).or_insert(0);
+            let key = if *count == 0 {
+                value.name.clone()
+            } else {
+                format!("{}#{}", value.name, count)
+            };
+            *count += 1;
+            key
+        })
+        .collect()
+}
+
+/// The name of anonymous array entries
+const ARRAY_ENTRY: &str = "[*]";
+
+/// The number of anonymous array entries among the values
+fn array_entries(values: &[Stalker2CfgValue]) -> usize {
+    values
+        .iter()
+        .filter(|value| value.name == ARRAY_ENTRY)
+        .count()
+}
+
+/// The contents of a value, including the values nested in it
+fn value_content(cfg: &Stalker2Cfg, value: &Stalker2CfgValue) -> String {
+    match struct_values(cfg, value) {
+        Some(values) => format!(
+            "{}{{{}}}",
+            struct_meta(cfg, value).unwrap_or_default(),
+            values
+                .iter()
+                .map(|value| format!("{}={}", value.name, value_content(cfg, value)))
+                .collect::<Vec<_>>()
+                .join(";")
+        ),
+        None => value.value.clone().unwrap_or_default(),
+    }
+}
+
+/// Keys the values for merging. Anonymous array entries that are also in the base are matched by position, but the ones
+/// appended after them are matched by content, so entries that different mods append are combined rather than
+/// conflicting
+fn keyed_values<'a>(
+    cfg: &Stalker2Cfg,
+    values: &'a [Stalker2CfgValue],
+    base_entries: usize,
+) -> IndexMap<String, &'a Stalker2CfgValue> {
+    let mut keys = sibling_keys(values);
+    let mut appended: HashMap<String, usize> = HashMap::new();
+    let mut entry_idx = 0;
+
+    for (key, value) in keys.iter_mut().zip(values.iter()) {
+        if value.name != ARRAY_ENTRY {
+            continue;
+        }
+
+        if entry_idx >= base_entries {
+            let content = value_content(cfg, value);
+            let count = appended.entry(content.clone()).or_insert(0);
+            *key = if *count == 0 {
+                format!("{}+{}", ARRAY_ENTRY, content)
+            } else {
+                format!("{}+{}#{}", ARRAY_ENTRY, content, count)
+            };
+            *count += 1;
+        }
+        entry_idx += 1;
+    }
+
+    keys.into_iter().zip(values.iter()).collect()
+}
+
+fn struct_values<'a>(
+    cfg: &'a Stalker2Cfg,
+    value: &Stalker2CfgValue,
+) -> Option<&'a [Stalker2CfgValue]> {
+    value.struct_key.map(|key| {
+        cfg.structs
+            .get(key)
+            .expect("struct keys always point to an existing struct")
+            .values
+            .as_slice()
+    })
+}
+
+fn struct_meta<'a>(cfg: &'a Stalker2Cfg, value: &Stalker2CfgValue) -> Option<&'a str> {
+    value.struct_key.map(|key| {
+        cfg.structs
+            .get(key)
+            .expect("struct keys always point to an existing struct")
+            .meta
+            .as_str()
+    })
+}
+
+fn values_equal(
+    a_cfg: &Stalker2Cfg,
+    a: &Stalker2CfgValue,
+    b_cfg: &Stalker2Cfg,
+    b: &Stalker2CfgValue,
+) -> bool {
+    if a.name != b.name || a.value != b.value {
+        return false;
+    }
+
+    match (struct_values(a_cfg, a), struct_values(b_cfg, b)) {
+        (Some(a_values), Some(b_values)) => {
+            struct_meta(a_cfg, a) == struct_meta(b_cfg, b)
+                && a_values.len() == b_values.len()
+                && a_values
+                    .iter()
+                    .zip(b_values.iter())
+                    .all(|(a, b)| values_equal(a_cfg, a, b_cfg, b))
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// How a value is shown in conflict reports
+fn display_value(cfg: &Stalker2Cfg, value: &Stalker2CfgValue) -> String {
+    match struct_meta(cfg, value) {
+        Some(meta) => format!("struct.begin{}", meta),
+        None => value.value.clone().unwrap_or_default(),
+    }
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}/{}", parent, key)
+    }
+}
+
+type CfgValues<'a> = (&'a Stalker2Cfg, &'a [Stalker2CfgValue]);
+type CfgValue<'a> = (&'a Stalker2Cfg, &'a Stalker2CfgValue);
+
+/// The state of an in-progress three-way merge
+struct CfgMerge {
+    merged: Stalker2Cfg,
+    conflicts: Vec<ValueConflict>,
+}
+
+impl CfgMerge {
+    /// Three-way merge of a single string. When both sides changed it, theirs wins and the
+    /// conflict is recorded
+    fn merge_scalar<'a>(
+        &mut self,
+        path: &str,
+        base: Option<&'a str>,
+        ours: &'a str,
+        theirs: &'a str,
+    ) -> &'a str {
+        if ours == theirs || base == Some(theirs) {
+            return ours;
+        }
+
+        if base != Some(ours) {
+            self.conflicts.push(ValueConflict {
+                path: path.to_string(),
+                base: base.map(str::to_string),
+                ours: Some(ours.to_string()),
+                theirs: Some(theirs.to_string()),
+            });
+        }
+
+        theirs
+    }
+
+    /// Deep copies a value (and any nested structs) from `cfg` into the merged cfg
+    fn copy_value(
+        &mut self,
+        parent: Option<DefaultKey>,
+        cfg: &Stalker2Cfg,
+        value: &Stalker2CfgValue,
+    ) -> Stalker2CfgValue {
+        let struct_key = value.struct_key.map(|key| {
+            let source = cfg
+                .structs
+                .get(key)
+                .expect("struct keys always point to an existing struct");
+
+            let new_key = self
+                .merged
+                .structs
+                .insert(source.with_layout(source.meta.clone(), parent));
+
+            let values = source
+                .values
+                .iter()
+                .map(|v| self.copy_value(Some(new_key), cfg, v))
+                .collect();
+
+            self.merged
+                .structs
+                .get_mut(new_key)
+                .expect("we just inserted this struct")
+                .values = values;
+
+            new_key
+        });
+
+        value.with_layout(value.value.clone(), struct_key)
+    }
+
+    /// Three-way merge of a single value that exists on both our and their side
+    fn merge_value(
+        &mut self,
+        parent: Option<DefaultKey>,
+        path: &str,
+        base: Option<CfgValue>,
+        (our_cfg, our): CfgValue,
+        (their_cfg, their): CfgValue,
+    ) -> Stalker2CfgValue {
+        match (struct_values(our_cfg, our), struct_values(their_cfg, their)) {
+            (Some(our_values), Some(their_values)) => {
+                let base_struct = base.and_then(|(cfg, value)| {
+                    struct_values(cfg, value).map(|values| (cfg, values, struct_meta(cfg, value)))
+                });
+
+                let meta = self
+                    .merge_scalar(
+                        path,
+                        base_struct.and_then(|(_, _, meta)| meta),
+                        struct_meta(our_cfg, our).expect("our value is a struct"),
+                        struct_meta(their_cfg, their).expect("their value is a struct"),
+                    )
+                    .to_string();
+
+                let our_struct = our_cfg
+                    .structs
+                    .get(our.struct_key.expect("our value is a struct"))
+                    .expect("struct keys always point to an existing struct");
+                let new_key = self
+                    .merged
+                    .structs
+                    .insert(our_struct.with_layout(meta, parent));
+
+                let values = self.merge_values(
+                    Some(new_key),
+                    path,
+                    base_struct.map(|(cfg, values, _)| (cfg, values)),
+                    (our_cfg, our_values),
+                    (their_cfg, their_values),
+                );
+
+                self.merged
+                    .structs
+                    .get_mut(new_key)
+                    .expect("we just inserted this struct")
+                    .values = values;
+
+                our.with_layout(None, Some(new_key))
+            }
+            (None, None) => {
+                let base_value = base.and_then(|(_, value)| value.value.as_deref());
+                let value = self.merge_scalar(
+                    path,
+                    base_value,
+                    our.value.as_deref().unwrap_or_default(),
+                    their.value.as_deref().unwrap_or_default(),
+                );
+
+                // Keep the layout (and comments) of whichever side the value came from
+                let layout = if our.value.as_deref() == Some(value) {
+                    our
+                } else {
+                    their
+                };
+
+                layout.with_layout(Some(value.to_string()), None)
+            }
+            // One side replaced a value with a struct (or vice versa), so take whichever side changed it
+            _ => {
+                let unchanged = |cfg, value| {
+                    base.is_some_and(|(base_cfg, base_value)| {
+                        values_equal(base_cfg, base_value, cfg, value)
+                    })
+                };
+
+                if unchanged(their_cfg, their) {
+                    return self.copy_value(parent, our_cfg, our);
+                }
+
+                if !unchanged(our_cfg, our) {
+                    self.conflicts.push(ValueConflict {
+                        path: path.to_string(),
+                        base: base.map(|(cfg, value)| display_value(cfg, value)),
+                        ours: Some(display_value(our_cfg, our)),
+                        theirs: Some(display_value(their_cfg, their)),
+                    });
+                }
+
+                self.copy_value(parent, their_cfg, their)
+            }
+        }
+    }
+
+    /// Three-way merge of the values of a struct (or the root of the file). Values are matched by
+    /// name rather than by position so insertions on either side don't shift the values that follow
+    /// them
+    fn merge_values(
+        &mut self,
+        parent: Option<DefaultKey>,
+        path: &str,
+        base: Option<CfgValues>,
+        (our_cfg, our_values): CfgValues,
+        (their_cfg, their_values): CfgValues,
+    ) -> Vec<Stalker2CfgValue> {
+        let base_entries = base.map_or(0, |(_, values)| array_entries(values));
+        let base_map = base
+            .map(|(cfg, values)| keyed_values(cfg, values, base_entries))
+            .unwrap_or_default();
+        let our_map = keyed_values(our_cfg, our_values, base_entries);
+        let their_map = keyed_values(their_cfg, their_values, base_entries);
+
+        // Start with our ordering and place any of their new keys right after the key that precedes
+        // them in their file
+        let mut order: Vec<&String> = our_map.keys().collect();
+        let mut anchor: Option<usize> = None;
+        for key in their_map.keys() {
+            match order.iter().position(|k| *k == key) {
+                Some(idx) => anchor = Some(idx),
+                None => {
+                    let idx = anchor.map_or(0, |idx| idx + 1);
+                    order.insert(idx, key);
+                    anchor = Some(idx);
+                }
+            }
+        }
+
+        let mut result = Vec::new();
+
+        for key in order {
+            let value_path = join_path(path, key);
+            let base_value = base.and_then(|(cfg, _)| base_map.get(key).map(|v| (cfg, *v)));
+            let our_value = our_map.get(key).map(|v| (our_cfg, *v));
+            let their_value = their_map.get(key).map(|v| (their_cfg, *v));
+
+            let value = match (base_value, our_value, their_value) {
+                (_, Some(our), Some(their)) => {
+                    Some(self.merge_value(parent, &value_path, base_value, our, their))
+                }
+                (None, Some((cfg, value)), None) => Some(self.copy_value(parent, cfg, value)),
+                (None, None, Some((cfg, value))) => Some(self.copy_value(parent, cfg, value)),
+                // Deleted by them. Their deletion wins even if we changed the value
+                (Some((base_cfg, base)), Some((cfg, value)), None) => {
+                    if !values_equal(base_cfg, base, cfg, value) {
+                        self.conflicts.push(ValueConflict {
+                            path: value_path,
+                            base: Some(display_value(base_cfg, base)),
+                            ours: Some(display_value(cfg, value)),
+                            theirs: None,
+                        });
+                    }
+
+                    None
+                }
+                // Deleted by us. Only bring it back if they changed it
+                (Some((base_cfg, base)), None, Some((cfg, value))) => {
+                    if values_equal(base_cfg, base, cfg, value) {
+                        None
+                    } else {
+                        self.conflicts.push(ValueConflict {
+                            path: value_path,
+                            base: Some(display_value(base_cfg, base)),
+                            ours: None,
+                            theirs: Some(display_value(cfg, value)),
+                        });
+
+                        Some(self.copy_value(parent, cfg, value))
+                    }
+                }
+                (_, None, None) => None,
+            };
+
+            result.extend(value);
+        }
+
+        result
+    }
+}
+
+/// Three-way merge of cfg files. Values and nested structs are identified by their name path
+/// (i.e. `TradePrototypes/Trader_1/SellModifier`), so independent additions and edits from
+/// different mods are combined. When both sides change the same value, theirs wins and the
+/// conflict is returned alongside the merged cfg
 pub fn merge_cfg_structs(
     base: &Stalker2Cfg,
     our: &Stalker2Cfg,
     their: &Stalker2Cfg,
-) -> anyhow::Result<Stalker2Cfg> {
-    let base_json = serde_json::to_value(&base)?.to_string();
-    let our_json = serde_json::to_value(&our)?.to_string();
-    let their_json = serde_json::to_value(&their)?.to_string();
-
-    Ok(serde_json::from_str::<Stalker2Cfg>(
-        &merge::merge_json_strings(&base_json, &our_json, &their_json)?,
-    )?)
+) -> anyhow::Result<(Stalker2Cfg, Vec<ValueConflict>)> {
+    let mut merge = CfgMerge {
+        merged: Stalker2Cfg {
+            name: our.name.clone(),
+            structs: SlotMap::new(),
+            root_values: Vec::new(),
+            trailing_lines: our.trailing_lines.clone(),
+            line_ending: our.line_ending.clone(),
+            final_newline: our.final_newline,
+            bom: our.bom,
+        },
+        conflicts: Vec::new(),
+    };
+
+    merge.merged.root_values = merge.merge_values(
+        None,
+        "",
+        Some((base, &base.root_values)),
+        (our, &our.root_values),
+        (their, &their.root_values),
+    );
+
+    Ok((merge.merged, merge.conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cfg: &str) -> Stalker2Cfg {
+        Stalker2Cfg::from_str("Test.cfg".to_string(), cfg).unwrap()
+    }
+
+    #[test]
+    fn unedited_files_are_printed_as_they_were() {
+        let cfgs = [
+            // Comments, trailing comments and uneven indentation
+            "// Header comment\n\nBoar : struct.begin {refurl=Base.cfg;refkey=[0]} // trailing\n  MaxHP = 200  // was 300\n\t\tSpeed=1.5f\n   // Comment before the end\n    struct.end // done\n// Footer\n",
+            // CRLF line endings, a byte order mark and no final newline
+            "\u{feff}Boar : struct.begin\r\n   MaxHP = 200 // was 300\r\n   Items : struct.begin\r\n      [*] = Bite\r\n   struct.end\r\nstruct.end",
+            // No final newline with LF line endings
+            "Value = 1\nOther = 2",
+        ];
+
+        for cfg in cfgs {
+            assert_eq!(parse(cfg).to_string(), cfg);
+        }
+    }
+
+    /// Merges three cfgs, returning the merged file and the paths of the conflicts
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, Vec<String>) {
+        let (merged, conflicts) =
+            merge_cfg_structs(&parse(base), &parse(ours), &parse(theirs)).unwrap();
+        let paths = conflicts
+            .into_iter()
+            .map(|conflict| conflict.path)
+            .collect();
+
+        (merged.to_string(), paths)
+    }
+
+    #[test]
+    fn moved_values_are_matched_by_name() {
+        let (merged, conflicts) = merge(
+            "A = 1\nB = 2\nC = 3\n",
+            "C = 3\nA = 1\nB = 2\n",
+            "A = 1\nB = 20\nC = 3\n",
+        );
+
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+        assert_eq!(merged, "C = 3\nA = 1\nB = 20\n");
+    }
+
+    #[test]
+    fn inserted_values_from_both_sides_are_kept() {
+        let (merged, conflicts) = merge(
+            "A = 1\nC = 3\n",
+            "A = 1\nB = 2\nC = 3\n",
+            "A = 1\nC = 3\nD = 4\n",
+        );
+
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+        assert_eq!(merged, "A = 1\nB = 2\nC = 3\nD = 4\n");
+    }
+
+    #[test]
+    fn deletions_against_edits_are_conflicts() {
+        // Their edit brings back the value we deleted
+        let (merged, conflicts) = merge("A = 1\nB = 2\n", "A = 1\n", "A = 1\nB = 5\n");
+        assert_eq!(merged, "A = 1\nB = 5\n");
+        assert_eq!(conflicts, ["B"]);
+
+        // Their deletion wins over our edit
+        let (merged, conflicts) = merge("A = 1\nB = 2\n", "A = 1\nB = 5\n", "A = 1\n");
+        assert_eq!(merged, "A = 1\n");
+        assert_eq!(conflicts, ["B"]);
+
+        // Deleting a value the other side left alone isn't a conflict
+        let (merged, conflicts) = merge("A = 1\nB = 2\n", "A = 1\n", "A = 1\nB = 2\n");
+        assert_eq!(merged, "A = 1\n");
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+    }
+
+    #[test]
+    fn nested_structs_are_merged_value_by_value() {
+        let base = "Boar : struct.begin\n   Speed = 1\n   Stats : struct.begin\n      MaxHP = 100\n      Armor = 5\n   struct.end\nstruct.end\n";
+        let ours = "Boar : struct.begin\n   Speed = 2\n   Stats : struct.begin\n      MaxHP = 80\n      Armor = 5\n   struct.end\nstruct.end\n";
+        let theirs = "Boar : struct.begin\n   Speed = 1\n   Stats : struct.begin\n      MaxHP = 60\n      Armor = 10\n   struct.end\nstruct.end\n";
+
+        let (merged, conflicts) = merge(base, ours, theirs);
+
+        assert_eq!(
+            merged,
+            "Boar : struct.begin\n   Speed = 2\n   Stats : struct.begin\n      MaxHP = 60\n      Armor = 10\n   struct.end\nstruct.end\n"
+        );
+        assert_eq!(conflicts, ["Boar/Stats/MaxHP"]);
+    }
+
+    #[test]
+    fn appended_array_entries_are_combined() {
+        let base = parse(
+            "Abilities : struct.begin\n   Items : struct.begin\n      [*] = Bite\n   struct.end\nstruct.end\n",
+        );
+        let ours = parse(
+            "Abilities : struct.begin\n   Items : struct.begin\n      [*] = Bite\n      [*] = Howl\n   struct.end\nstruct.end\n",
+        );
+        let theirs = parse(
+            "Abilities : struct.begin\n   Items : struct.begin\n      [*] = Bite\n      [*] = Leap\n   struct.end\nstruct.end\n",
+        );
+
+        let (merged, conflicts) = merge_cfg_structs(&base, &ours, &theirs).unwrap();
+        let values: Vec<String> = merged.values_by_path().into_values().collect();
+
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+        for entry in ["Bite", "Howl", "Leap"] {
+            assert_eq!(values.iter().filter(|value| *value == entry).count(), 1);
+        }
+    }
 }
@@ -8,20 +8,79 @@ use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::fmt::Formatter;
 
-use crate::merge;
+use crate::merge::ValueConflict;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UnrealIni {
     sections: IndexMap<String, UnrealIniSection>,
 }
 
+/// The operator in front of an ini key. Unreal uses these to build up arrays across config files
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UnrealIniOp {
+    /// `Key=Value`
+    Set,
+    /// `+Key=Value` adds the value to the array if it's not already in it
+    Add,
+    /// `.Key=Value` adds the value to the array even if it's already in it
+    AddDuplicate,
+    /// `-Key=Value` removes the value from the array
+    Remove,
+    /// `!Key=Value` clears the array
+    Clear,
+}
+
+impl UnrealIniOp {
+    fn from_prefix(c: char) -> Option<Self> {
+        match c {
+            '+' => Some(Self::Add),
+            '.' => Some(Self::AddDuplicate),
+            '-' => Some(Self::Remove),
+            '!' => Some(Self::Clear),
+            _ => None,
+        }
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Self::Set => "",
+            Self::Add => "+",
+            Self::AddDuplicate => ".",
+            Self::Remove => "-",
+            Self::Clear => "!",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UnrealIniEntry {
+    pub op: UnrealIniOp,
+    pub value: String,
+}
+
 impl Display for UnrealIni {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        for (section_name, section) in &self.sections {
-            writeln!(f, "[{}]", section_name)?;
+        // Keys before the first section are kept in a section without a name, which is written first and without a
+        // header so they stay outside of any section
+        let sections = self
+            .sections
+            .iter()
+            .filter(|(section_name, _)| section_name.is_empty())
+            .chain(
+                self.sections
+                    .iter()
+                    .filter(|(section_name, _)| !section_name.is_empty()),
+            );
+
+        for (section_name, section) in sections {
+            if !section_name.is_empty() {
+                writeln!(f, "[{}]", section_name)?;
+            }
 
-            for (key, value) in &section.values {
-                writeln!(f, "{} = {}", key, value)?;
+            for (key, entries) in &section.values {
+                for entry in entries {
+                    writeln!(f, "{}{} = {}", entry.op.prefix(), key, entry.value)?;
+                }
             }
 
             writeln!(f)?;
@@ -31,25 +90,66 @@ impl Display for UnrealIni {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The values of a section keyed by name without the operator prefix. Every line for a key is kept in order, so
+/// array operations like `+Paths=...` don't overwrite each other
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 struct UnrealIniSection {
-    values: IndexMap<String, String>,
+    values: IndexMap<String, Vec<UnrealIniEntry>>,
+}
+
+/// Returns the value of a key that is set exactly once without an operator
+fn scalar_value(entries: &[UnrealIniEntry]) -> Option<&String> {
+    match entries {
+        [entry] if entry.op == UnrealIniOp::Set => Some(&entry.value),
+        _ => None,
+    }
 }
 
 impl UnrealIni {
-    pub fn from_str(s: &str) -> Self {
-        let mut sections = IndexMap::new();
+    /// Flattens the ini into a map of `[Section] Key` to values. Array keys are broken down further into one
+    /// `[Section] +Key=Value` entry per line
+    pub fn values_by_path(&self) -> IndexMap<String, String> {
+        let mut result = IndexMap::new();
+
+        for (section_name, section) in &self.sections {
+            for (key, entries) in &section.values {
+                if let Some(value) = scalar_value(entries) {
+                    result.insert(format!("[{}] {}", section_name, key), value.clone());
+                    continue;
+                }
+
+                for entry in entries {
+                    result.insert(
+                        format!(
+                            "[{}] {}{}={}",
+                            section_name,
+                            entry.op.prefix(),
+                            key,
+                            entry.value
+                        ),
+                        entry.value.clone(),
+                    );
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Parses the contents of an ini file
+    pub fn parse(s: &str) -> Self {
+        let mut sections: IndexMap<String, UnrealIniSection> = IndexMap::new();
 
-        fn new_section(input: &str) -> IResult<&str, (String, IndexMap<String, String>)> {
+        fn new_section(input: &str) -> IResult<&str, String> {
             let (input, _) = multispace0(input)?;
             let (input, _) = tag("[")(input)?;
             let (input, name) = take_till(|c| c == ']')(input)?;
             let (input, _) = tag("]")(input)?;
 
-            Ok((input, (name.to_string(), IndexMap::new())))
+            Ok((input, name.to_string()))
         }
 
-        fn value(input: &str) -> IResult<&str, (String, String)> {
+        fn value(input: &str) -> IResult<&str, (String, UnrealIniEntry)> {
             let (input, _) = multispace0(input)?;
             let (input, key) = take_till(|c: char| c.is_whitespace() || c == '=')(input)?;
             let (input, _) = multispace0(input)?;
@@ -57,7 +157,21 @@ impl UnrealIni {
             let (input, _) = multispace0(input)?;
             let (input, value) = not_line_ending(input)?;
 
-            Ok((input, (key.to_string(), value.to_string())))
+            let (op, key) = match key.chars().next().and_then(UnrealIniOp::from_prefix) {
+                Some(op) => (op, &key[1..]),
+                None => (UnrealIniOp::Set, key),
+            };
+
+            Ok((
+                input,
+                (
+                    key.to_string(),
+                    UnrealIniEntry {
+                        op,
+                        value: value.to_string(),
+                    },
+                ),
+            ))
         }
 
         let mut current_section = String::new();
@@ -69,17 +183,24 @@ impl UnrealIni {
                 continue;
             }
 
-            if let Ok((_, (name, values))) = new_section(line) {
-                sections.insert(name.clone(), UnrealIniSection { values });
-                current_section = name.clone();
-            } else if let Ok((_, (key, value))) = value(line) {
+            if let Ok((_, name)) = new_section(line) {
+                // Sections can be repeated, in which case the values are combined
                 sections
-                    .get_mut(&current_section)
-                    .expect(
-                        "should always be able to get the current section since it's never deleted",
-                    )
+                    .entry(name.clone())
+                    .or_insert_with(|| UnrealIniSection {
+                        values: IndexMap::new(),
+                    });
+                current_section = name;
+            } else if let Ok((_, (key, entry))) = value(line) {
+                sections
+                    .entry(current_section.clone())
+                    .or_insert_with(|| UnrealIniSection {
+                        values: IndexMap::new(),
+                    })
                     .values
-                    .insert(key, value);
+                    .entry(key)
+                    .or_default()
+                    .push(entry);
             }
         }
 
@@ -87,16 +208,238 @@ impl UnrealIni {
     }
 }
 
+/// Three-way merge of the lines of an array key. Lines removed by either side are removed and lines added by
+/// either side are kept, so additions from several mods are combined. `.Key=Value` lines can repeat, so they are
+/// counted: as many copies are removed or added as either side removed or added
+fn merge_ini_entries(
+    base: &[UnrealIniEntry],
+    ours: &[UnrealIniEntry],
+    theirs: &[UnrealIniEntry],
+) -> Vec<UnrealIniEntry> {
+    let count = |entries: &[UnrealIniEntry], entry: &UnrealIniEntry| {
+        entries.iter().filter(|e| *e == entry).count()
+    };
+    let mut result = ours.to_vec();
+
+    for (idx, entry) in base.iter().enumerate() {
+        // Repeated lines are handled together at their first occurrence
+        if base[..idx].contains(entry) {
+            continue;
+        }
+
+        let removed = if entry.op == UnrealIniOp::AddDuplicate {
+            count(base, entry).saturating_sub(count(theirs, entry))
+        } else {
+            usize::from(!theirs.contains(entry))
+        };
+        for _ in 0..removed {
+            if let Some(idx) = result.iter().position(|e| e == entry) {
+                result.remove(idx);
+            }
+        }
+    }
+
+    for (idx, entry) in theirs.iter().enumerate() {
+        if theirs[..idx].contains(entry) {
+            continue;
+        }
+
+        let added = if entry.op == UnrealIniOp::AddDuplicate {
+            count(theirs, entry).saturating_sub(count(base, entry))
+        } else {
+            usize::from(!base.contains(entry) && !ours.contains(entry))
+        };
+        result.extend(std::iter::repeat_n(entry.clone(), added));
+    }
+
+    result
+}
+
+/// Three-way merge of a single ini value. When both sides changed it, theirs wins and the
+/// conflict is recorded
+fn merge_ini_value(
+    path: String,
+    base: Option<&String>,
+    ours: Option<&String>,
+    theirs: Option<&String>,
+    conflicts: &mut Vec<ValueConflict>,
+) -> Option<String> {
+    if ours == theirs || base == theirs {
+        return ours.cloned();
+    }
+
+    if base != ours {
+        conflicts.push(ValueConflict {
+            path,
+            base: base.cloned(),
+            ours: ours.cloned(),
+            theirs: theirs.cloned(),
+        });
+    }
+
+    theirs.cloned()
+}
+
+/// Three-way merge of ini files on a per-key basis. When both sides change the same key, theirs
+/// wins and the conflict is returned alongside the merged ini
 pub fn merge_unreal_inis(
     base: &UnrealIni,
     our: &UnrealIni,
     their: &UnrealIni,
-) -> anyhow::Result<UnrealIni> {
-    let base_json = serde_json::to_value(&base)?.to_string();
-    let our_json = serde_json::to_value(&our)?.to_string();
-    let their_json = serde_json::to_value(&their)?.to_string();
-
-    Ok(serde_json::from_str::<UnrealIni>(
-        &merge::merge_json_strings(&base_json, &our_json, &their_json)?,
-    )?)
+) -> anyhow::Result<(UnrealIni, Vec<ValueConflict>)> {
+    let mut sections: IndexMap<String, UnrealIniSection> = IndexMap::new();
+    let mut conflicts = Vec::new();
+    let empty = IndexMap::new();
+
+    let section_names = our.sections.keys().chain(
+        their
+            .sections
+            .keys()
+            .filter(|name| !our.sections.contains_key(*name)),
+    );
+
+    for section_name in section_names {
+        let base_values = base.sections.get(section_name).map(|s| &s.values);
+        let our_values = our.sections.get(section_name).map(|s| &s.values);
+        let their_values = their.sections.get(section_name).map(|s| &s.values);
+
+        // A section that one side removed entirely stays removed unless the other side changed it
+        if base_values.is_some() && (our_values.is_none() || their_values.is_none()) {
+            let remaining = our_values.or(their_values);
+            if remaining == base_values {
+                continue;
+            }
+        }
+
+        let base_values = base_values.unwrap_or(&empty);
+        let our_values = our_values.unwrap_or(&empty);
+        let their_values = their_values.unwrap_or(&empty);
+
+        let keys = our_values
+            .keys()
+            .chain(their_values.keys().filter(|k| !our_values.contains_key(*k)));
+
+        let mut values = IndexMap::new();
+        for key in keys {
+            let base_entries = base_values.get(key).map_or(&[][..], Vec::as_slice);
+            let our_entries = our_values.get(key).map_or(&[][..], Vec::as_slice);
+            let their_entries = their_values.get(key).map_or(&[][..], Vec::as_slice);
+
+            // Keys that are only ever set once are merged as a single value, everything else is merged as an array
+            let is_scalar =
+                |entries: &[UnrealIniEntry]| entries.is_empty() || scalar_value(entries).is_some();
+
+            let entries =
+                if is_scalar(base_entries) && is_scalar(our_entries) && is_scalar(their_entries) {
+                    merge_ini_value(
+                        format!("[{}] {}", section_name, key),
+                        scalar_value(base_entries),
+                        scalar_value(our_entries),
+                        scalar_value(their_entries),
+                        &mut conflicts,
+                    )
+                    .map(|value| {
+                        vec![UnrealIniEntry {
+                            op: UnrealIniOp::Set,
+                            value,
+                        }]
+                    })
+                    .unwrap_or_default()
+                } else {
+                    merge_ini_entries(base_entries, our_entries, their_entries)
+                };
+
+            if !entries.is_empty() {
+                values.insert(key.clone(), entries);
+            }
+        }
+
+        sections.insert(section_name.clone(), UnrealIniSection { values });
+    }
+
+    Ok((UnrealIni { sections }, conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_before_the_first_section_have_no_header() {
+        let ini = UnrealIni::parse("Version=2\n\n[Core.Log]\nLogTemp=Verbose\n");
+
+        assert_eq!(
+            ini.to_string(),
+            "Version = 2\n\n[Core.Log]\nLogTemp = Verbose\n\n"
+        );
+        assert_eq!(
+            UnrealIni::parse(&ini.to_string()).to_string(),
+            ini.to_string()
+        );
+    }
+
+    /// Merges three inis, returning the merged lines of `[Paths]`
+    fn merge_paths(base: &str, ours: &str, theirs: &str) -> Vec<String> {
+        let (merged, conflicts) = merge_unreal_inis(
+            &UnrealIni::parse(base),
+            &UnrealIni::parse(ours),
+            &UnrealIni::parse(theirs),
+        )
+        .unwrap();
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+
+        merged
+            .to_string()
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('['))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn array_additions_and_removals_are_combined() {
+        let merged = merge_paths(
+            "[Paths]\n+Paths=A\n+Paths=B\n",
+            "[Paths]\n+Paths=A\n+Paths=B\n+Paths=C\n-Paths=B\n",
+            "[Paths]\n+Paths=A\n+Paths=D\n",
+        );
+
+        assert_eq!(
+            merged,
+            ["+Paths = A", "+Paths = C", "-Paths = B", "+Paths = D"]
+        );
+    }
+
+    #[test]
+    fn clears_are_kept_in_place() {
+        let merged = merge_paths(
+            "[Paths]\n+Paths=A\n",
+            "[Paths]\n!Paths=ClearArray\n+Paths=A\n",
+            "[Paths]\n+Paths=A\n+Paths=B\n",
+        );
+
+        assert_eq!(merged, ["!Paths = ClearArray", "+Paths = A", "+Paths = B"]);
+    }
+
+    #[test]
+    fn duplicate_additions_are_counted() {
+        // Both add another copy of an existing line
+        let merged = merge_paths(
+            "[Paths]\n.Paths=A\n",
+            "[Paths]\n.Paths=A\n.Paths=A\n",
+            "[Paths]\n.Paths=A\n.Paths=A\n.Paths=B\n",
+        );
+        assert_eq!(
+            merged,
+            [".Paths = A", ".Paths = A", ".Paths = A", ".Paths = B"]
+        );
+
+        // Removing one copy keeps the others
+        let merged = merge_paths(
+            "[Paths]\n.Paths=A\n.Paths=A\n+Paths=C\n",
+            "[Paths]\n.Paths=A\n.Paths=A\n+Paths=C\n+Paths=D\n",
+            "[Paths]\n.Paths=A\n+Paths=C\n",
+        );
+        assert_eq!(merged, [".Paths = A", "+Paths = C", "+Paths = D"]);
+    }
 }
@@ -0,0 +1,47 @@
+use std::{fs, io, path::Path};
+
+use unreal_pak_mod_manager::ModpackBuilder;
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            copy_dir(&path, &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(&path, to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the example from the README, in a copy so the build outputs don't end up in the repository
+#[test]
+fn example_builds() {
+    let example_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example");
+    let dir = std::env::temp_dir().join(format!("upmm_example_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    copy_dir(&example_dir, &dir).unwrap();
+
+    let report = ModpackBuilder::from_config_file(&dir.join("config.toml"))
+        .unwrap()
+        .build()
+        .unwrap();
+
+    assert!(report.pak_path.is_file());
+    assert!(report.provenance_path.is_file());
+    assert!(report
+        .files
+        .iter()
+        .any(|file| file.ends_with("Bloodsucker.cfg")));
+
+    // The staging directory of the first build is reused
+    ModpackBuilder::from_config_file(&dir.join("config.toml"))
+        .unwrap()
+        .build()
+        .unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
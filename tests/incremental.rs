@@ -0,0 +1,103 @@
+use std::{fs, path::Path};
+
+use unreal_pak_mod_manager::{BuildReport, ModpackBuilder};
+
+fn write_mod(dir: &Path, name: &str, contents: &str) {
+    fs::create_dir_all(dir.join("mods").join(name)).unwrap();
+    fs::write(dir.join("mods").join(name).join("vals.cfg"), contents).unwrap();
+}
+
+fn build(dir: &Path) -> BuildReport {
+    ModpackBuilder::from_config_file(&dir.join("config.toml"))
+        .unwrap()
+        .build()
+        .unwrap()
+}
+
+/// Changing a mod reuses the merges of the mods before it, and merges it and every mod after it again
+#[test]
+fn unchanged_mods_before_the_first_change_are_reused() {
+    let dir = std::env::temp_dir().join(format!("upmm_incremental_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("config.toml"),
+        "name = \"incremental\"\nstaging_dir = \"staging\"\nmods_dir = \"mods\"\n",
+    )
+    .unwrap();
+    write_mod(&dir, "mod_a", "A = 1\nB = 1\nC = 1\n");
+    write_mod(&dir, "mod_b", "A = 1\nB = 2\nC = 1\n");
+    write_mod(&dir, "mod_c", "A = 1\nB = 1\nC = 3\n");
+    let staged = || fs::read_to_string(dir.join("staging/vals.cfg")).unwrap();
+
+    let report = build(&dir);
+    let mods = report.merge.mods.clone();
+    assert_eq!(mods.len(), 3);
+    assert!(report.merge.reused_mods.is_empty());
+    assert_eq!(staged(), "A = 1\nB = 2\nC = 3\n");
+
+    // Nothing changed, so every merge is reused
+    let report = build(&dir);
+    assert_eq!(report.merge.reused_mods, mods);
+    assert_eq!(staged(), "A = 1\nB = 2\nC = 3\n");
+
+    // Changing the second mod merges it and the mods after it again
+    write_mod(&dir, "mod_b", "A = 1\nB = 5\nC = 1\n");
+    let report = build(&dir);
+    assert_eq!(report.merge.reused_mods, mods[..1]);
+    assert_eq!(staged(), "A = 1\nB = 5\nC = 3\n");
+
+    // Changing the last mod only merges it again
+    write_mod(&dir, "mod_c", "A = 1\nB = 1\nC = 7\n");
+    let report = build(&dir);
+    assert_eq!(report.merge.reused_mods, mods[..2]);
+    assert_eq!(staged(), "A = 1\nB = 5\nC = 7\n");
+
+    // Changing the first mod merges everything again. Without base paks the first mod's file is the merge base, so the
+    // result must match a build from scratch
+    write_mod(&dir, "mod_a", "A = 9\nB = 1\nC = 1\n");
+    let report = build(&dir);
+    assert!(report.merge.reused_mods.is_empty());
+    let incremental = staged();
+
+    ModpackBuilder::from_config_file(&dir.join("config.toml"))
+        .unwrap()
+        .clean()
+        .unwrap();
+    build(&dir);
+    assert_eq!(incremental, staged());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Archives are found whatever the case of their extension, and are reused like any other mod
+#[test]
+fn archives_with_uppercase_extensions_are_merged_and_reused() {
+    let dir = std::env::temp_dir().join(format!("upmm_archive_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("mods")).unwrap();
+    fs::write(
+        dir.join("config.toml"),
+        "name = \"archive\"\nstaging_dir = \"staging\"\nmods_dir = \"mods\"\n",
+    )
+    .unwrap();
+
+    let mut archive = zip::ZipWriter::new(fs::File::create(dir.join("mods/mod_a.ZIP")).unwrap());
+    archive
+        .start_file("Data/vals.cfg", zip::write::SimpleFileOptions::default())
+        .unwrap();
+    std::io::Write::write_all(&mut archive, b"A = 1\n").unwrap();
+    archive.finish().unwrap();
+
+    let report = build(&dir);
+    assert_eq!(report.merge.mods.len(), 1);
+    assert_eq!(
+        fs::read_to_string(dir.join("staging/Data/vals.cfg")).unwrap(),
+        "A = 1\n"
+    );
+
+    let report = build(&dir);
+    assert_eq!(report.merge.reused_mods, report.merge.mods);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
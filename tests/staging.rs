@@ -0,0 +1,74 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use unreal_pak_mod_manager::ModpackBuilder;
+
+/// Creates a directory with a config using the given staging and mods directories, and an empty mods directory
+fn setup(name: &str, staging_dir: &str, mods_dir: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("upmm_staging_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join(mods_dir)).unwrap();
+    fs::write(
+        dir.join("config.toml"),
+        format!(
+            "name = \"test\"\nstaging_dir = \"{}\"\nmods_dir = \"{}\"\n",
+            staging_dir, mods_dir
+        ),
+    )
+    .unwrap();
+
+    dir
+}
+
+fn builder(dir: &Path) -> ModpackBuilder {
+    ModpackBuilder::from_config_file(&dir.join("config.toml")).unwrap()
+}
+
+#[test]
+fn staging_dir_containing_the_config_is_not_deleted() {
+    let dir = setup("config", ".", "mods");
+
+    assert!(builder(&dir).clean().is_err());
+    assert!(dir.join("config.toml").is_file());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn staging_dir_containing_the_mods_is_not_deleted() {
+    let dir = setup("mods", "staging", "staging/mods");
+
+    assert!(builder(&dir).clean().is_err());
+    assert!(dir.join("staging/mods").is_dir());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn staging_dir_not_created_by_a_build_is_not_deleted() {
+    let dir = setup("foreign", "staging", "mods");
+    fs::create_dir_all(dir.join("staging")).unwrap();
+    fs::write(dir.join("staging/notes.txt"), "keep me").unwrap();
+
+    assert!(builder(&dir).clean().is_err());
+    assert!(dir.join("staging/notes.txt").is_file());
+
+    // --force-staging deletes it anyway
+    assert!(builder(&dir).force(true).clean().unwrap());
+    assert!(!dir.join("staging").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn empty_staging_dir_is_deleted() {
+    let dir = setup("empty", "staging", "mods");
+    fs::create_dir_all(dir.join("staging")).unwrap();
+
+    assert!(builder(&dir).clean().unwrap());
+    assert!(!dir.join("staging").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}